@@ -1,7 +1,9 @@
-extern crate gcc;
+extern crate cc;
 
 fn main() {
-    gcc::Build::new()
+    println!("cargo:rerun-if-changed=src/bis_c.c");
+
+    cc::Build::new()
         .file("src/bis_c.c")
         .compile("libbis_c.a");
 }
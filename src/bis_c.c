@@ -15,6 +15,11 @@
 #include <unistd.h>
 #include <string.h>
 #include <sys/ioctl.h>
+#include <poll.h>
+#include <errno.h>
+#include <fcntl.h>
+#include <signal.h>
+#include <time.h>
 
 struct bis_error_info_t {
   char *error_str;
@@ -26,6 +31,8 @@ struct bis_term_size_t {
   unsigned short cols;
 };
 
+static int bis_resize_pipe[2] = {-1, -1};
+
 static char bis_term_info_set = 0;
 static struct termios bis_term_info;
 
@@ -34,10 +41,10 @@ struct bis_error_info_t bis_error_info = {
   .is_errno = 0
 };
 
-int bis_prepare_terminal() {
+int bis_prepare_terminal(int fd) {
   struct termios terminfo_p;
   // get terminal options
-  if (tcgetattr(STDOUT_FILENO, &terminfo_p) != 0) {
+  if (tcgetattr(fd, &terminfo_p) != 0) {
     bis_error_info.error_str = "Error getting terminal attributes";
     bis_error_info.is_errno = 1;
     return -1;
@@ -59,7 +66,7 @@ int bis_prepare_terminal() {
   terminfo_p.c_lflag &= ~ECHO;
 
   // set terminal options
-  if (tcsetattr(STDOUT_FILENO, TCSAFLUSH, &terminfo_p) != 0) {
+  if (tcsetattr(fd, TCSAFLUSH, &terminfo_p) != 0) {
     bis_error_info.error_str = "Error setting terminal attributes";
     bis_error_info.is_errno = 1;
     return -1;
@@ -69,7 +76,7 @@ int bis_prepare_terminal() {
   return 0;
 }
 
-int bis_restore_terminal() {
+int bis_restore_terminal(int fd) {
   if (bis_term_info_set != 1) {
     bis_error_info.error_str = "bis_restore_terminal called before bis_prepare_terminal";
     bis_error_info.is_errno = 0;
@@ -77,7 +84,7 @@ int bis_restore_terminal() {
   }
 
   // set terminal options
-  if (tcsetattr(STDOUT_FILENO, TCSANOW, &bis_term_info) != 0) {
+  if (tcsetattr(fd, TCSANOW, &bis_term_info) != 0) {
     bis_error_info.error_str = "Error restoring terminal attributes";
     bis_error_info.is_errno = 1;
     return -1;
@@ -87,10 +94,10 @@ int bis_restore_terminal() {
   return 0;
 }
 
-int bis_get_terminal_size(struct bis_term_size_t *size) {
+int bis_get_terminal_size(int fd, struct bis_term_size_t *size) {
   struct winsize term_size;
   // request the terminal size
-  if (ioctl(STDOUT_FILENO, TIOCGWINSZ, &term_size) != 0) {
+  if (ioctl(fd, TIOCGWINSZ, &term_size) != 0) {
     bis_error_info.error_str = "ioctl call failed";
     bis_error_info.is_errno = 1;
     return -1;
@@ -103,3 +110,99 @@ int bis_get_terminal_size(struct bis_term_size_t *size) {
   // return success
   return 0;
 }
+
+int bis_wait_input(int fd, int timeout) {
+  struct pollfd input = {
+    .fd = fd,
+    .events = POLLIN,
+    .revents = 0
+  };
+  struct timespec start, now;
+  int remaining = timeout;
+  int result;
+
+  if (timeout > 0 && clock_gettime(CLOCK_MONOTONIC, &start) < 0) {
+    bis_error_info.error_str = "Error reading the clock";
+    bis_error_info.is_errno = 1;
+    return -1;
+  }
+
+  // wait for input, retrying if a signal interrupts us
+  while ((result = poll(&input, 1, remaining)) < 0 && errno == EINTR) {
+    if (timeout <= 0) {
+      // nothing to count down
+      continue;
+    }
+
+    // only wait for what's left of the timeout
+    if (clock_gettime(CLOCK_MONOTONIC, &now) < 0) {
+      bis_error_info.error_str = "Error reading the clock";
+      bis_error_info.is_errno = 1;
+      return -1;
+    }
+
+    long elapsed = (now.tv_sec - start.tv_sec) * 1000 + (now.tv_nsec - start.tv_nsec) / 1000000;
+
+    if (elapsed >= timeout) {
+      return 0;
+    }
+
+    remaining = timeout - (int)elapsed;
+  }
+
+  if (result < 0) {
+    bis_error_info.error_str = "Error polling for input";
+    bis_error_info.is_errno = 1;
+    return -1;
+  }
+
+  // return 1 if input is ready, 0 on timeout
+  return result;
+}
+
+static void bis_resize_handler(int signum) {
+  int saved_errno = errno;
+  char byte = 0;
+
+  (void) signum;
+
+  // only async-signal-safe calls in here, the reader does the real work
+  if (write(bis_resize_pipe[1], &byte, 1) < 0) {
+    // the pipe is full, so a resize is already waiting to be read
+  }
+
+  errno = saved_errno;
+}
+
+int bis_watch_resize(void) {
+  struct sigaction action;
+
+  if (pipe(bis_resize_pipe) != 0) {
+    bis_error_info.error_str = "Error creating resize pipe";
+    bis_error_info.is_errno = 1;
+    return -1;
+  }
+
+  // never block in the signal handler
+  if (fcntl(bis_resize_pipe[1], F_SETFL, O_NONBLOCK) != 0) {
+    bis_error_info.error_str = "Error setting up resize pipe";
+    bis_error_info.is_errno = 1;
+    return -1;
+  }
+
+  memset(&action, 0, sizeof(struct sigaction));
+  action.sa_handler = bis_resize_handler;
+  sigemptyset(&action.sa_mask);
+
+  // restart reads on the terminal instead of failing them
+  action.sa_flags = SA_RESTART;
+
+  if (sigaction(SIGWINCH, &action, (struct sigaction *) 0) != 0) {
+    bis_error_info.error_str = "Error installing resize handler";
+    bis_error_info.is_errno = 1;
+    return -1;
+  }
+
+  // return the end to read resizes from
+  return bis_resize_pipe[0];
+}
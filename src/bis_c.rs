@@ -13,6 +13,7 @@
 // limitations under the License.
 
 // bindings into bis_c.c
+use std::os::unix::io::RawFd;
 
 mod c {
     use libc::*;
@@ -35,9 +36,11 @@ mod c {
     extern "C" {
         static mut bis_error_info: bis_error_info_t;
 
-        pub fn bis_prepare_terminal() -> c_int;
-        pub fn bis_restore_terminal() -> c_int;
-        pub fn bis_get_terminal_size(size: *mut bis_term_size_t) -> c_int;
+        pub fn bis_prepare_terminal(fd: c_int) -> c_int;
+        pub fn bis_restore_terminal(fd: c_int) -> c_int;
+        pub fn bis_get_terminal_size(fd: c_int, size: *mut bis_term_size_t) -> c_int;
+        pub fn bis_wait_input(fd: c_int, timeout: c_int) -> c_int;
+        pub fn bis_watch_resize() -> c_int;
     }
 
     pub fn c_panic() -> ! {
@@ -53,35 +56,57 @@ mod c {
     }
 }
 
-pub fn prepare_terminal() {
+pub fn prepare_terminal(fd: RawFd) {
     unsafe {
         debug!("Preparing terminal");
 
-        if c::bis_prepare_terminal() != 0 {
+        if c::bis_prepare_terminal(fd) != 0 {
             c::c_panic();
         }
     }
 }
 
-pub fn restore_terminal() {
+pub fn restore_terminal(fd: RawFd) {
     unsafe {
         debug!("Restoring terminal");
 
-        if c::bis_restore_terminal() != 0 {
+        if c::bis_restore_terminal(fd) != 0 {
             c::c_panic();
         }
     }
 }
 
-pub fn get_terminal_size() -> (u16, u16) {
+pub fn get_terminal_size(fd: RawFd) -> (u16, u16) {
     unsafe {
         debug!("Getting terminal size");
         let mut term_size = c::bis_term_size_t { rows: 0, cols: 0 };
 
-        if c::bis_get_terminal_size(&mut term_size) != 0 {
+        if c::bis_get_terminal_size(fd, &mut term_size) != 0 {
             c::c_panic();
         }
 
         (term_size.rows, term_size.cols)
     }
 }
+
+// true if fd has input before the timeout, in milliseconds
+pub fn wait_input(fd: RawFd, timeout: u32) -> bool {
+    unsafe {
+        match c::bis_wait_input(fd, timeout.min(i32::MAX as u32) as i32) {
+            -1 => c::c_panic(),
+            ready => ready > 0,
+        }
+    }
+}
+
+// returns a pipe that gets a byte every time the terminal is resized
+pub fn watch_resize() -> RawFd {
+    unsafe {
+        debug!("Watching for resizes");
+
+        match c::bis_watch_resize() {
+            -1 => c::c_panic(),
+            fd => fd,
+        }
+    }
+}
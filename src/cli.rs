@@ -0,0 +1,424 @@
+// Copyright 2015 Jerome Rasky <jerome@rasky.co>
+//
+// Licensed under the Apache License, version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     <http://www.apache.org/licenses/LICENSE-2.0>
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
+// License for the specific language concerning governing permissions and
+// limitations under the License.
+use std::fmt;
+
+use config::Config;
+
+pub const USAGE: &str = "\
+Usage: bis2 [OPTIONS]
+       bis2 <COMMAND>
+
+Search shell history interactively.
+
+Commands:
+  init <SHELL>           Print integration code for bash, zsh or fish
+  stats                  Show what bis2 knows about your history
+  record <LINE>          Remember how a command went, for shell integrations
+  migrate                Convert completions from the old json file
+
+Options:
+  -q, --query <QUERY>    Start with the given query
+  -f, --history-file <PATH>
+                         Search this history file instead of the configured
+                         ones, may be given more than once
+  -n, --no-completions   Don't use or update the completions file
+  -H, --height <ROWS>    Number of matches to show
+  -p, --print-only       Draw on the terminal and print the match on stdout
+  -s, --exit-status <CODE>
+                         How the recorded command exited
+  -d, --duration <SECONDS>
+                         How long the recorded command ran
+  -V, --version          Print the version and exit
+  -h, --help             Print this help and exit
+";
+
+// short options that take a value
+const SHORT_VALUES: &str = "qfHsd";
+
+// long options that don't
+const SWITCHES: &[&str] = &["--no-completions", "--print-only", "--version", "--help"];
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum Command {
+    Search,
+    Init(String),
+    Stats,
+    Record(String),
+    Migrate,
+    Help,
+    Version,
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub command: Command,
+    pub query: Option<String>,
+    pub history_files: Vec<String>,
+    pub no_completions: bool,
+    pub height: Option<usize>,
+    pub print_only: bool,
+    pub exit_status: Option<i32>,
+    pub duration: Option<f64>,
+}
+
+#[derive(Debug)]
+pub enum CliError {
+    UnknownOption(String),
+    UnknownCommand(String),
+    UnexpectedArgument(String),
+    MissingValue(String),
+    InvalidValue(String, String),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::UnknownOption(option) => write!(f, "unknown option {}", option),
+            CliError::UnknownCommand(command) => write!(f, "unknown command {}", command),
+            CliError::UnexpectedArgument(arg) => write!(f, "unexpected argument {}", arg),
+            CliError::MissingValue(option) => write!(f, "{} needs a value", option),
+            CliError::InvalidValue(option, value) => write!(f, "invalid value for {}: {:?}", option, value),
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            command: Command::Search,
+            query: None,
+            history_files: vec![],
+            no_completions: false,
+            height: None,
+            print_only: false,
+            exit_status: None,
+            duration: None,
+        }
+    }
+}
+
+impl Options {
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Options, CliError> {
+        let mut options = Options::default();
+        let mut args = args.into_iter();
+        let mut positional = vec![];
+
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                // recorded command lines can start with a dash
+                positional.extend(args.by_ref());
+                break;
+            }
+
+            // accept both "--option value" and "--option=value"
+            let flags = match arg.find('=') {
+                Some(split) if arg.starts_with("--") => {
+                    vec![(arg[..split].to_string(), Some(arg[split + 1..].to_string()))]
+                }
+                _ if arg.len() > 2 && arg.starts_with('-') && !arg.starts_with("--") => split_short(&arg),
+                _ => vec![(arg.clone(), None)],
+            };
+
+            for (name, inline) in flags {
+                if options.flag(name, inline, &mut args)? {
+                    positional.push(arg.clone());
+                }
+            }
+        }
+
+        let mut positional = positional.into_iter();
+
+        if let Some(command) = positional.next() {
+            let parsed = match command.as_str() {
+                "init" => {
+                    let shell = positional.next()
+                        .ok_or_else(|| CliError::MissingValue(command.clone()))?;
+                    Command::Init(shell)
+                }
+                "stats" => Command::Stats,
+                "migrate" => Command::Migrate,
+                "record" => {
+                    let line = positional.next()
+                        .ok_or_else(|| CliError::MissingValue(command.clone()))?;
+
+                    if options.exit_status.is_none() {
+                        return Err(CliError::MissingValue("--exit-status".into()));
+                    }
+
+                    Command::Record(line)
+                }
+                _ => return Err(CliError::UnknownCommand(command)),
+            };
+
+            if let Some(extra) = positional.next() {
+                return Err(CliError::UnexpectedArgument(extra));
+            }
+
+            // --help and --version win over any command
+            if options.command == Command::Search {
+                options.command = parsed;
+            }
+        }
+
+        Ok(options)
+    }
+
+    // one option, taking its value from the next argument if it wasn't given
+    // inline, and returning true if it wasn't an option at all
+    fn flag<I: Iterator<Item = String>>(&mut self, name: String, inline: Option<String>, args: &mut I) -> Result<bool, CliError> {
+        // switches take no value, so "--print-only=no" is a mistake rather than a yes
+        if let (true, Some(raw)) = (SWITCHES.contains(&name.as_str()), inline.as_ref()) {
+            return Err(CliError::InvalidValue(name.clone(), raw.clone()));
+        }
+
+        let mut value = |name: &str| {
+            inline.clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| CliError::MissingValue(name.into()))
+        };
+
+        match name.as_str() {
+            "-q" | "--query" => self.query = Some(value(&name)?),
+            "-f" | "--history-file" => self.history_files.push(value(&name)?),
+            "-n" | "--no-completions" => self.no_completions = true,
+            "-H" | "--height" => {
+                let raw = value(&name)?;
+                match raw.parse() {
+                    Ok(height) if height > 0 => self.height = Some(height),
+                    _ => return Err(CliError::InvalidValue(name, raw)),
+                }
+            }
+            "-p" | "--print-only" => self.print_only = true,
+            "-s" | "--exit-status" => {
+                let raw = value(&name)?;
+                match raw.parse() {
+                    Ok(status) => self.exit_status = Some(status),
+                    _ => return Err(CliError::InvalidValue(name, raw)),
+                }
+            }
+            "-d" | "--duration" => {
+                let raw = value(&name)?;
+                match raw.parse::<f64>() {
+                    Ok(duration) if duration.is_finite() && duration >= 0.0 => self.duration = Some(duration),
+                    _ => return Err(CliError::InvalidValue(name, raw)),
+                }
+            }
+            "-V" | "--version" => self.command = Command::Version,
+            "-h" | "--help" => self.command = Command::Help,
+            _ if name.starts_with('-') && name.len() > 1 => {
+                return Err(CliError::UnknownOption(name));
+            }
+            _ => return Ok(true),
+        }
+
+        Ok(false)
+    }
+
+    // integrations that can't pass arguments hand over the shell's line in BIS2_QUERY
+    pub fn default_query(&mut self, fallback: Option<String>) {
+        if self.query.is_none() {
+            self.query = fallback;
+        }
+    }
+
+    // command line flags take priority over the config file and environment
+    pub fn apply(&self, config: &mut Config) {
+        if !self.history_files.is_empty() {
+            config.history = Some(self.history_files.clone());
+        }
+
+        if let Some(height) = self.height {
+            config.match_number = height;
+        }
+
+        if self.no_completions {
+            config.completions = false;
+        }
+    }
+}
+
+// "-np" is "-n -p", and "-qgit" is "-q git"
+fn split_short(arg: &str) -> Vec<(String, Option<String>)> {
+    let mut flags = vec![];
+
+    for (index, chr) in arg.char_indices().skip(1) {
+        let rest = &arg[index + chr.len_utf8()..];
+
+        if SHORT_VALUES.contains(chr) && !rest.is_empty() {
+            flags.push((format!("-{}", chr), Some(rest.to_string())));
+            break;
+        }
+
+        flags.push((format!("-{}", chr), None));
+    }
+
+    flags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, CliError> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn query() {
+        assert_eq!(parse(&[]).unwrap().query, None);
+        assert_eq!(parse(&["--query", "git log"]).unwrap().query, Some("git log".into()));
+        assert_eq!(parse(&["--query=git log"]).unwrap().query, Some("git log".into()));
+        assert_eq!(parse(&["-q", "make"]).unwrap().query, Some("make".into()));
+        // an empty line is still the line the shell had
+        assert_eq!(parse(&["--query="]).unwrap().query, Some("".into()));
+        assert_eq!(parse(&["--query=a=b"]).unwrap().query, Some("a=b".into()));
+        // whatever follows is the value, even if it looks like an option
+        assert_eq!(parse(&["--query", "--help"]).unwrap().query, Some("--help".into()));
+    }
+
+    #[test]
+    fn query_fallback() {
+        let fallback = || Some("from env".to_string());
+
+        let mut options = parse(&[]).unwrap();
+        options.default_query(fallback());
+        assert_eq!(options.query, Some("from env".into()));
+
+        // the command line wins, even when it's empty
+        let mut options = parse(&["--query=git"]).unwrap();
+        options.default_query(fallback());
+        assert_eq!(options.query, Some("git".into()));
+
+        let mut options = parse(&["--query="]).unwrap();
+        options.default_query(fallback());
+        assert_eq!(options.query, Some("".into()));
+
+        let mut options = parse(&[]).unwrap();
+        options.default_query(None);
+        assert_eq!(options.query, None);
+    }
+
+    #[test]
+    fn flags() {
+        let options = parse(&["-n", "--print-only", "-H", "5", "-f", "a", "--history-file=b"]).unwrap();
+
+        assert!(options.no_completions);
+        assert!(options.print_only);
+        assert_eq!(options.height, Some(5));
+        assert_eq!(options.history_files, vec!["a", "b"]);
+        assert_eq!(options.command, Command::Search);
+
+        for arg in &["--print-only=no", "--no-completions=", "--help=yes"] {
+            match parse(&[arg]) {
+                Err(CliError::InvalidValue(option, _)) => assert!(arg.starts_with(&option)),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn combined_short_flags() {
+        let options = parse(&["-np"]).unwrap();
+
+        assert!(options.no_completions);
+        assert!(options.print_only);
+
+        let options = parse(&["-npq", "git"]).unwrap();
+
+        assert!(options.no_completions);
+        assert_eq!(options.query, Some("git".into()));
+
+        let options = parse(&["-pqgit", "-H3"]).unwrap();
+
+        assert!(options.print_only);
+        assert_eq!(options.query, Some("git".into()));
+        assert_eq!(options.height, Some(3));
+
+        match parse(&["-nx"]) {
+            Err(CliError::UnknownOption(option)) => assert_eq!(option, "-x"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(parse(&["init", "zsh"]).unwrap().command, Command::Init("zsh".into()));
+        assert_eq!(parse(&["stats"]).unwrap().command, Command::Stats);
+        assert_eq!(parse(&["migrate"]).unwrap().command, Command::Migrate);
+        assert_eq!(parse(&["-V"]).unwrap().command, Command::Version);
+        assert_eq!(parse(&["init", "zsh", "--help"]).unwrap().command, Command::Help);
+    }
+
+    #[test]
+    fn record() {
+        let options = parse(&["record", "--exit-status", "1", "-d", "2.5", "--", "-rf --no-preserve-root"]).unwrap();
+
+        assert_eq!(options.command, Command::Record("-rf --no-preserve-root".into()));
+        assert_eq!(options.exit_status, Some(1));
+        assert_eq!(options.duration, Some(2.5));
+
+        let options = parse(&["-s", "-1", "record", "--", "--"]).unwrap();
+
+        assert_eq!(options.command, Command::Record("--".into()));
+        assert_eq!(options.exit_status, Some(-1));
+
+        match parse(&["record", "ls"]) {
+            Err(CliError::MissingValue(option)) => assert_eq!(option, "--exit-status"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown() {
+        match parse(&["--frobnicate"]) {
+            Err(CliError::UnknownOption(option)) => assert_eq!(option, "--frobnicate"),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        match parse(&["--frobnicate=yes"]) {
+            Err(CliError::UnknownOption(option)) => assert_eq!(option, "--frobnicate"),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        match parse(&["search"]) {
+            Err(CliError::UnknownCommand(command)) => assert_eq!(command, "search"),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        match parse(&["stats", "now"]) {
+            Err(CliError::UnexpectedArgument(arg)) => assert_eq!(arg, "now"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn missing_values() {
+        for args in [&["--query"][..], &["-q"], &["-nq"], &["--height"], &["init"], &["record"]].iter() {
+            match parse(args) {
+                Err(CliError::MissingValue(_)) => {}
+                other => panic!("unexpected {:?} for {:?}", other, args),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_values() {
+        for args in [&["-H", "0"][..], &["--height=tall"], &["-s", "one"], &["-d", "-1"], &["-d", "inf"]].iter() {
+            match parse(args) {
+                Err(CliError::InvalidValue(_, _)) => {}
+                other => panic!("unexpected {:?} for {:?}", other, args),
+            }
+        }
+    }
+}
@@ -0,0 +1,277 @@
+// Copyright 2015 Jerome Rasky <jerome@rasky.co>
+//
+// Licensed under the Apache License, version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     <http://www.apache.org/licenses/LICENSE-2.0>
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
+// License for the specific language concerning governing permissions and
+// limitations under the License.
+
+// subcommands that run without the interactive search
+use std::collections::HashSet;
+
+use std::env;
+
+use cli::Options;
+use config::Config;
+use threads;
+use store::Store;
+use scoring;
+use types::Change;
+
+const INIT_BASH: &str = include_str!("shell/bis2.bash");
+const INIT_ZSH: &str = include_str!("shell/bis2.zsh");
+const INIT_FISH: &str = include_str!("shell/bis2.fish");
+
+pub fn init(shell: &str) -> i32 {
+    let script = match shell {
+        "bash" => INIT_BASH,
+        "zsh" => INIT_ZSH,
+        "fish" => INIT_FISH,
+        _ => {
+            eprintln!("bis2: unsupported shell {:?}, expected bash, zsh or fish", shell);
+            return 2;
+        }
+    };
+
+    print!("{}", script);
+
+    0
+}
+
+pub fn stats(config: &Config) -> i32 {
+    let mut lines = HashSet::new();
+    let mut total = 0;
+
+    println!("History:");
+
+    for (index, source) in threads::history_sources(config).iter().enumerate() {
+        match source.load(index) {
+            Ok(entries) => {
+                let timestamped = entries.iter().filter(|entry| entry.timestamp.is_some()).count();
+
+                println!("  {}: {} commands, {} timestamped", source.path.display(), entries.len(), timestamped);
+
+                total += entries.len();
+                lines.extend(entries.into_iter().map(|entry| entry.line));
+            }
+            Err(error) => {
+                println!("  {}: unreadable ({})", source.path.display(), error);
+            }
+        }
+    }
+
+    println!("  {} commands, {} unique", total, lines.len());
+
+    if config.completions {
+        let store = Store::home();
+        let completions = store.load(&config.retention).completions(&config.retention, scoring::now());
+
+        println!("Completions:");
+        println!("  {}", store.path().display());
+        println!("  {} commands chosen in {} directories", completions.len(), completions.directories());
+        println!("  {} commands recorded", completions.recorded());
+    } else {
+        println!("Completions: disabled");
+    }
+
+    match Config::path() {
+        Some(ref path) if path.exists() => println!("Config: {}", path.display()),
+        _ => println!("Config: defaults"),
+    }
+
+    0
+}
+
+// called by the shell after every command
+pub fn record(config: &Config, options: &Options, line: &str) -> i32 {
+    record_to(&Store::home(), config, options, line)
+}
+
+fn record_to(store: &Store, config: &Config, options: &Options, line: &str) -> i32 {
+    if !config.completions || line.trim().is_empty() {
+        return 0;
+    }
+
+    let status = options.exit_status.unwrap_or(0);
+    let seconds = options.duration.unwrap_or(0.0);
+    let directory = env::current_dir().ok();
+
+    let result = store.record(vec![Change::Ran(line.to_string(), status, seconds, directory)], &config.retention);
+
+    match result {
+        Ok(()) => 0,
+        Err(error) => {
+            eprintln!("bis2: failed to record command: {}", error);
+            1
+        }
+    }
+}
+
+// convert the old json completions to the index
+pub fn migrate(config: &Config) -> i32 {
+    migrate_from(&Store::home(), config)
+}
+
+fn migrate_from(store: &Store, config: &Config) -> i32 {
+    match store.migrate(&config.retention) {
+        Ok(Some(lines)) => {
+            println!("Moved {} commands from {} to {}", lines,
+                     store.legacy_path().display(), store.path().display());
+            0
+        }
+        Ok(None) if store.legacy_path().exists() => {
+            // an older bis2 wrote it after the index was made, and there's no
+            // telling how much of it the index already has
+            eprintln!("bis2: {} already exists, leaving {} from an older bis2 as it is",
+                      store.path().display(), store.legacy_path().display());
+            1
+        }
+        Ok(None) => {
+            println!("Nothing to migrate");
+            0
+        }
+        Err(error) => {
+            eprintln!("bis2: failed to migrate completions: {}", error);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::prelude::*;
+
+    use std::process::{Command, Stdio};
+    use std::fs;
+    use std::io;
+
+    use types::{Completions, Runs};
+    use testing::Directory;
+
+    fn recorded(store: &Store, config: &Config) -> Completions {
+        store.load(&config.retention).completions(&config.retention, scoring::now())
+    }
+
+    fn runs<'a>(completions: &'a Completions, line: &str) -> Option<&'a Runs> {
+        completions.entries().into_iter()
+            .find(|&(known, _, _)| known == line)
+            .and_then(|(_, _, runs)| runs)
+    }
+
+    // runs `shell -n` on the script, or None when the shell isn't installed
+    fn syntax_ok(shell: &str, script: &str) -> Option<bool> {
+        let mut child = match Command::new(shell).arg("-n").stdin(Stdio::piped()).spawn() {
+            Ok(child) => child,
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => return None,
+            Err(error) => panic!("failed to run {}: {}", shell, error),
+        };
+
+        child.stdin.take().unwrap().write_all(script.as_bytes()).unwrap();
+
+        Some(child.wait().unwrap().success())
+    }
+
+    #[test]
+    fn init_shells() {
+        assert_eq!(init("bash"), 0);
+        assert_eq!(init("zsh"), 0);
+        assert_eq!(init("fish"), 0);
+    }
+
+    #[test]
+    fn init_unsupported() {
+        assert_eq!(init("tcsh"), 2);
+        assert_eq!(init("Bash"), 2);
+        assert_eq!(init(""), 2);
+    }
+
+    #[test]
+    fn init_scripts_parse() {
+        for &(shell, script) in [("bash", INIT_BASH), ("zsh", INIT_ZSH), ("fish", INIT_FISH)].iter() {
+            match syntax_ok(shell, script) {
+                Some(ok) => assert!(ok, "{} rejected its init script", shell),
+                None => eprintln!("{} isn't installed, not checking its init script", shell),
+            }
+        }
+    }
+
+    #[test]
+    fn record_skips_blank_lines() {
+        let directory = Directory::new("blank");
+        let store = directory.store();
+        let config = Config::default();
+
+        assert_eq!(record_to(&store, &config, &Options::default(), ""), 0);
+        assert_eq!(record_to(&store, &config, &Options::default(), " \t\n"), 0);
+
+        assert_eq!(recorded(&store, &config).recorded(), 0);
+        assert!(!store.path().exists());
+    }
+
+    #[test]
+    fn record_without_completions() {
+        let directory = Directory::new("disabled");
+        let store = directory.store();
+        let config = Config { completions: false, ..Config::default() };
+
+        assert_eq!(record_to(&store, &config, &Options::default(), "make"), 0);
+
+        assert!(!store.path().exists());
+    }
+
+    #[test]
+    fn record_status_and_duration() {
+        let directory = Directory::new("runs");
+        let store = directory.store();
+        let config = Config::default();
+
+        let failed = Options { exit_status: Some(127), duration: Some(0.5), ..Options::default() };
+        let passed = Options { exit_status: Some(0), duration: Some(2.0), ..Options::default() };
+
+        assert_eq!(record_to(&store, &config, &failed, "mkae"), 0);
+        assert_eq!(record_to(&store, &config, &passed, "make"), 0);
+        assert_eq!(record_to(&store, &config, &passed, "make"), 0);
+
+        let completions = recorded(&store, &config);
+
+        let typo = runs(&completions, "mkae").unwrap();
+        assert_eq!((typo.count, typo.failures, typo.seconds), (1, 1, 0.5));
+
+        let make = runs(&completions, "make").unwrap();
+        assert_eq!((make.count, make.failures, make.seconds), (2, 0, 4.0));
+        assert_eq!(make.directory, env::current_dir().ok());
+
+        // no status counts as a success
+        assert_eq!(record_to(&store, &config, &Options::default(), "ls"), 0);
+
+        let ls = runs(&recorded(&store, &config), "ls").cloned().unwrap();
+        assert_eq!((ls.count, ls.failures, ls.seconds), (1, 0, 0.0));
+    }
+
+    #[test]
+    fn migrate_reports_leftovers() {
+        let directory = Directory::new("migrate-leftovers");
+        let store = directory.store();
+        let config = Config::default();
+        let legacy = br#"{"info":{"make":[["/",2.0]]}}"#;
+
+        assert_eq!(migrate_from(&store, &config), 0);
+
+        fs::write(store.legacy_path(), legacy).unwrap();
+        assert_eq!(migrate_from(&store, &config), 0);
+        assert!(!store.legacy_path().exists());
+
+        // written again by an older bis2 that doesn't know about the index
+        fs::write(store.legacy_path(), legacy).unwrap();
+        assert_eq!(migrate_from(&store, &config), 1);
+        assert_eq!(fs::read(store.legacy_path()).unwrap(), legacy);
+    }
+}
@@ -0,0 +1,344 @@
+// Copyright 2015 Jerome Rasky <jerome@rasky.co>
+//
+// Licensed under the Apache License, version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     <http://www.apache.org/licenses/LICENSE-2.0>
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
+// License for the specific language concerning governing permissions and
+// limitations under the License.
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::fs::File;
+use std::fmt;
+
+use std::env;
+use std::io;
+
+use serde_json;
+use dirs;
+
+use scoring::Weights;
+use keymap::{Keymap, KeymapConfig};
+use theme::Theme;
+use types::Retention;
+use constants::*;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub match_number: usize,
+    pub num_threads: usize,
+    pub completion_score_factor: f32,
+    pub prompt: String,
+    pub finish: String,
+    pub match_select: String,
+    // milliseconds to wait after escape for the rest of a key sequence
+    pub escape_timeout: u32,
+    pub keymap: KeymapConfig,
+    pub theme: Theme,
+    // read and update ~/.bis2_completions
+    pub completions: bool,
+    // history sources, as "path" or "format=path"
+    pub history: Option<Vec<String>>,
+    pub scoring: Weights,
+    pub retention: Retention,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, serde_json::Error),
+    Env(&'static str, String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, error) => write!(f, "failed to read {}: {}", path.display(), error),
+            ConfigError::Parse(path, error) => write!(f, "invalid config in {}: {}", path.display(), error),
+            ConfigError::Env(name, value) => write!(f, "invalid value for {}: {:?}", name, value),
+            ConfigError::Invalid(reason) => write!(f, "invalid config: {}", reason),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            match_number: MATCH_NUMBER,
+            num_threads: NUM_THREADS,
+            completion_score_factor: COMPLETION_SCORE_FACTOR,
+            prompt: PROMPT.into(),
+            finish: FINISH.into(),
+            match_select: MATCH_SELECT.into(),
+            escape_timeout: ESCAPE_TIMEOUT,
+            keymap: KeymapConfig::default(),
+            theme: Theme::default(),
+            completions: true,
+            history: None,
+            scoring: Weights::default(),
+            retention: Retention::default(),
+        }
+    }
+}
+
+impl Config {
+    // the config file, then the environment, over the defaults, along with
+    // the keymap it describes
+    pub fn load() -> Result<(Config, Keymap), ConfigError> {
+        let mut config = match Config::path() {
+            Some(ref path) if path.exists() => Config::load_from(path)?,
+            _ => Config::default(),
+        };
+
+        config.apply_env()?;
+        let keymap = config.validate()?;
+
+        Ok((config, keymap))
+    }
+
+    pub fn path() -> Option<PathBuf> {
+        let base = match env::var_os("XDG_CONFIG_HOME") {
+            Some(base) => PathBuf::from(base),
+            None => dirs::home_dir()?.join(".config"),
+        };
+
+        Some(base.join("bis2").join("config.json"))
+    }
+
+    pub fn load_from(path: &PathBuf) -> Result<Config, ConfigError> {
+        trace!("Config path: {:?}", path);
+
+        let file = File::open(path).map_err(|error| ConfigError::Io(path.clone(), error))?;
+
+        serde_json::from_reader(io::BufReader::new(file))
+            .map_err(|error| ConfigError::Parse(path.clone(), error))
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        env_parse("BIS2_MATCH_NUMBER", &mut self.match_number)?;
+        env_parse("BIS2_NUM_THREADS", &mut self.num_threads)?;
+        env_parse("BIS2_COMPLETION_SCORE_FACTOR", &mut self.completion_score_factor)?;
+        env_parse("BIS2_PROMPT", &mut self.prompt)?;
+        env_parse("BIS2_FINISH", &mut self.finish)?;
+        env_parse("BIS2_MATCH_SELECT", &mut self.match_select)?;
+        env_parse("BIS2_ESCAPE_TIMEOUT", &mut self.escape_timeout)?;
+        env_parse("BIS2_KEYMAP", &mut self.keymap.mode)?;
+
+        if let Ok(list) = env::var("BIS2_HISTORY") {
+            let sources: Vec<String> = list.split(':')
+                .filter(|spec| !spec.is_empty())
+                .map(String::from)
+                .collect();
+
+            if !sources.is_empty() {
+                self.history = Some(sources);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<Keymap, ConfigError> {
+        if self.match_number == 0 {
+            return Err(ConfigError::Invalid("match_number must be at least 1".into()));
+        }
+
+        if self.num_threads == 0 {
+            return Err(ConfigError::Invalid("num_threads must be at least 1".into()));
+        }
+
+        if !self.completion_score_factor.is_finite() {
+            return Err(ConfigError::Invalid("completion_score_factor must be a finite number".into()));
+        }
+
+        if let Some(ref history) = self.history {
+            if history.is_empty() {
+                return Err(ConfigError::Invalid("history must list at least one file".into()));
+            }
+        }
+
+        self.theme.validate().map_err(ConfigError::Invalid)?;
+        self.retention.validate().map_err(ConfigError::Invalid)?;
+        self.scoring.validate().map_err(ConfigError::Invalid)?;
+
+        Keymap::create(&self.keymap).map_err(ConfigError::Invalid)
+    }
+}
+
+fn env_parse<T: FromStr>(name: &'static str, value: &mut T) -> Result<(), ConfigError> {
+    match env::var(name) {
+        Ok(raw) => {
+            *value = raw.parse().map_err(|_| ConfigError::Env(name, raw))?;
+            Ok(())
+        }
+        Err(env::VarError::NotPresent) => Ok(()),
+        Err(env::VarError::NotUnicode(raw)) => {
+            Err(ConfigError::Env(name, raw.to_string_lossy().into_owned()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::process;
+
+    use keymap::{Action, Binding, Mode, parse_key};
+    use theme::Color;
+
+    fn invalid(config: Config) -> String {
+        match config.validate() {
+            Err(ConfigError::Invalid(reason)) => reason,
+            Err(other) => panic!("unexpected {}", other),
+            Ok(_) => panic!("expected {:?} to be invalid", config),
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        let mut keymap = Config::default().validate().unwrap();
+
+        assert_eq!(keymap.lookup(parse_key("esc").unwrap()), Binding::Action(Action::Cancel));
+    }
+
+    #[test]
+    fn validation() {
+        assert!(invalid(Config { match_number: 0, ..Config::default() }).contains("match_number"));
+        assert!(invalid(Config { num_threads: 0, ..Config::default() }).contains("num_threads"));
+        assert!(invalid(Config { completion_score_factor: f32::NAN, ..Config::default() }).contains("completion_score_factor"));
+        assert!(invalid(Config { history: Some(vec![]), ..Config::default() }).contains("history"));
+
+        let mut config = Config::default();
+        config.keymap.bindings.insert("ctrl-nope".into(), Action::Accept);
+        assert!(invalid(config).contains("ctrl-nope"));
+
+        let mut config = Config::default();
+        config.theme.highlight.fg = Some(Color::Name("mauve".into()));
+        assert!(invalid(config).contains("mauve"));
+
+        let mut config = Config::default();
+        config.scoring.stale = -1.0;
+        assert!(invalid(config).contains("-1"));
+
+        let mut config = Config::default();
+        config.retention.half_life = 0.0;
+        assert!(invalid(config).contains("half_life"));
+
+        let mut config = Config::default();
+        config.scoring.failure = 2.0;
+        assert!(invalid(config).contains("failure"));
+    }
+
+    #[test]
+    fn keymap_from_config() {
+        let mut config = Config::default();
+        config.keymap.mode = Mode::Vi;
+
+        let mut keymap = config.validate().unwrap();
+
+        assert_eq!(keymap.lookup(parse_key("esc").unwrap()), Binding::Handled);
+    }
+
+    #[test]
+    fn from_file() {
+        let path = env::temp_dir().join(format!("bis2-config-test-{}.json", process::id()));
+
+        fs::write(&path, r#"{"match_number": 3, "prompt": "? ", "keymap": {"mode": "vi"}}"#).unwrap();
+        let config = Config::load_from(&path).unwrap();
+
+        assert_eq!(config.match_number, 3);
+        assert_eq!(config.prompt, "? ");
+        assert_eq!(config.keymap.mode, Mode::Vi);
+        assert_eq!(config.finish, FINISH);
+
+        fs::write(&path, r#"{"match_numbr": 3}"#).unwrap();
+        let error = Config::load_from(&path).unwrap_err();
+
+        let _ = fs::remove_file(&path);
+
+        match error {
+            ConfigError::Parse(_, _) => {}
+            other => panic!("unexpected {}", other),
+        }
+    }
+
+    // the only test that touches BIS2_ variables, so nothing else sees them change
+    #[test]
+    fn env_overrides() {
+        let set = |vars: &[(&str, &str)]| {
+            for &(name, value) in vars {
+                env::set_var(name, value);
+            }
+        };
+
+        let clear = |vars: &[(&str, &str)]| {
+            for &(name, _) in vars {
+                env::remove_var(name);
+            }
+        };
+
+        let vars = [
+            ("BIS2_MATCH_NUMBER", "7"),
+            ("BIS2_NUM_THREADS", "2"),
+            ("BIS2_COMPLETION_SCORE_FACTOR", "2.5"),
+            ("BIS2_PROMPT", "> "),
+            ("BIS2_FINISH", " = "),
+            ("BIS2_MATCH_SELECT", "* "),
+            ("BIS2_ESCAPE_TIMEOUT", "200"),
+            ("BIS2_KEYMAP", "vi"),
+            ("BIS2_HISTORY", "~/.bash_history::zsh=/tmp/zsh_history:"),
+        ];
+
+        set(&vars);
+        let mut config = Config { match_number: 20, ..Config::default() };
+        let result = config.apply_env();
+        clear(&vars);
+
+        result.unwrap();
+        assert_eq!(config.match_number, 7);
+        assert_eq!(config.num_threads, 2);
+        assert_eq!(config.completion_score_factor, 2.5);
+        assert_eq!(config.prompt, "> ");
+        assert_eq!(config.finish, " = ");
+        assert_eq!(config.match_select, "* ");
+        assert_eq!(config.escape_timeout, 200);
+        assert_eq!(config.keymap.mode, Mode::Vi);
+        assert_eq!(config.history, Some(vec!["~/.bash_history".to_string(), "zsh=/tmp/zsh_history".to_string()]));
+
+        // nothing set leaves the file's settings alone
+        let mut config = Config { match_number: 20, history: Some(vec!["a".into()]), ..Config::default() };
+        config.apply_env().unwrap();
+        assert_eq!(config.match_number, 20);
+        assert_eq!(config.history, Some(vec!["a".to_string()]));
+
+        for &(name, value) in [("BIS2_MATCH_NUMBER", "ten"), ("BIS2_NUM_THREADS", "-1"), ("BIS2_COMPLETION_SCORE_FACTOR", "lots"),
+                              ("BIS2_ESCAPE_TIMEOUT", "-1"), ("BIS2_KEYMAP", "nano")].iter() {
+            set(&[(name, value)]);
+            let result = Config::default().apply_env();
+            clear(&[(name, value)]);
+
+            match result {
+                Err(ConfigError::Env(failed, raw)) => assert_eq!((failed, raw.as_str()), (name, value)),
+                other => panic!("unexpected {:?} for {}", other, name),
+            }
+        }
+
+        // an empty list keeps the configured sources
+        set(&[("BIS2_HISTORY", ":")]);
+        let mut config = Config::default();
+        let result = config.apply_env();
+        clear(&[("BIS2_HISTORY", ":")]);
+
+        result.unwrap();
+        assert_eq!(config.history, None);
+    }
+}
@@ -14,22 +14,59 @@
 pub const MATCH_NUMBER: usize = 10;
 pub const NUM_THREADS: usize = 4;
 pub const COMPLETION_SCORE_FACTOR: f32 = 10.0;
+pub const HISTORY_PATH_SCORE: f32 = 10.0;
+
+// frecency buckets, (maximum age in seconds, weight)
+pub const FRECENCY_TIME_BUCKETS: [(u64, f32); 5] = [
+    (60 * 60 * 24, 100.0),
+    (60 * 60 * 24 * 7, 60.0),
+    (60 * 60 * 24 * 30, 30.0),
+    (60 * 60 * 24 * 90, 10.0),
+    (60 * 60 * 24 * 365, 2.0),
+];
+// frecency buckets without timestamps, (maximum commands ago, weight)
+pub const FRECENCY_POSITION_BUCKETS: [(usize, f32); 5] = [
+    (100, 100.0),
+    (500, 60.0),
+    (2000, 30.0),
+    (5000, 10.0),
+    (20000, 2.0),
+];
+pub const FRECENCY_STALE_WEIGHT: f32 = 0.5;
+pub const FRECENCY_FREQUENCY_EXPONENT: f32 = 0.5;
+pub const FRECENCY_DIRECTORY_WEIGHT: f32 = 1.0;
+pub const FRECENCY_FAILURE_WEIGHT: f32 = 0.5;
+
+// completions retention, in days
+pub const COMPLETION_HALF_LIFE: f32 = 90.0;
+pub const COMPLETION_MAX_AGE: u64 = 730;
+pub const COMPLETION_MAX_DIRECTORIES: usize = 20;
+// bytes of changes journaled before they're compacted into the index
+pub const COMPLETION_JOURNAL_SIZE: u64 = 64 * 1024;
+
+// exit status asking the shell to run the chosen line
+pub const EXECUTE_STATUS: i32 = 3;
+
+// milliseconds
+pub const ESCAPE_TIMEOUT: u32 = 50;
 
-pub const EOT: char = '\u{4}';
-pub const CTRL_C: char = '\u{03}';
-pub const CTRL_U: char = '\u{15}';
-pub const CTRL_R: char = '\u{12}';
-pub const CTRL_S: char = '\u{13}';
 pub const ESC: char = '\u{1b}';
 pub const BEL: char = '\u{7}';
 pub const DEL: char = '\u{7f}';
 pub const BSPC: char = '\u{8}';
 pub const CR: char = '\r';
 
-pub const PROMPT: &'static str = "Match: ";
-pub const FINISH: &'static str = " -> ";
-pub const MATCH_SELECT: &'static str = "-> ";
-pub const MATCH_PRE: &'static str = "\n";
+pub const PROMPT: &str = "Match: ";
+pub const FINISH: &str = " -> ";
+pub const MATCH_SELECT: &str = "-> ";
+pub const MATCH_PRE: &str = "\n";
+
+// xterm bracketed paste mode
+pub const PASTE_ON: &str = "\x1b[?2004h";
+pub const PASTE_OFF: &str = "\x1b[?2004l";
+pub const PASTE_END: &str = "\x1b[201~";
+// bytes of a paste kept for the query, anything after is dropped
+pub const PASTE_LIMIT: usize = 64 * 1024;
 
 // Copied from the rust standard library, so that I can use it in stable
 // https://tools.ietf.org/html/rfc3629
@@ -55,5 +92,5 @@ static UTF8_CHAR_WIDTH: [u8; 256] = [
 /// Given a first byte, determines how many bytes are in this UTF-8 character.
 #[inline]
 pub fn utf8_char_width(b: u8) -> usize {
-    return UTF8_CHAR_WIDTH[b as usize] as usize;
+    UTF8_CHAR_WIDTH[b as usize] as usize
 }
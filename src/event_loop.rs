@@ -13,26 +13,29 @@
 // limitations under the License.
 use threadpool::ThreadPool;
 
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::sync::mpsc::{Sender, Receiver};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread::JoinHandle;
 use std::iter::FromIterator;
-use std::fs::File;
+use std::collections::{HashMap, HashSet};
 
 use std::sync::mpsc;
 use std::env;
 use std::thread;
 
 use terminal::Terminal;
+use query::Query;
+use config::Config;
+use cli::Options;
 use flx::SearchBase;
-use dirs;
 use threads;
-use serde_json;
+use store::Store;
+use keymap::{Action, Keymap};
+use constants::*;
 
 use ui::*;
 use types::*;
-use constants::*;
 
 pub struct EventLoop {
     emit: Sender<Event>,
@@ -40,37 +43,57 @@ pub struct EventLoop {
     terminal: Terminal,
     escape: Escape,
     matches: Matches,
-    selected: usize,
-    query: String,
+    window: Window,
+    query: Query,
+    // the shell's line when we started, returned if nothing is chosen
+    original: Option<String>,
     success: bool,
+    // the shell should run the chosen line
+    execute: bool,
+    // deleted this session
+    hidden: HashSet<String>,
     input_thread: Option<JoinHandle<()>>,
     input_stop: Arc<AtomicBool>,
     search: Option<Arc<SearchBase>>,
     pool: ThreadPool,
     recent: Vec<String>,
-    completions: Option<Arc<Mutex<Completions>>>,
+    tags: HashMap<String, String>,
+    // how many matches to ask for, more as we scroll
+    limit: usize,
+    // waiting on a search
+    loading: bool,
+    config: Config,
 }
 
 impl EventLoop {
-    pub fn create() -> EventLoop {
+    pub fn create(config: Config, keymap: Keymap, options: &Options) -> EventLoop {
         let (emit, events) = mpsc::channel();
-        let (input_thread, input_stop) = threads::start_threads(emit.clone());
+        let (input_thread, input_stop) = threads::start_threads(emit.clone(), &config, keymap);
+        let terminal = Terminal::create(options.print_only);
+        let escape = Escape::create(&config);
+        let window = Window::new(escape.height(terminal.rows() as usize));
 
         EventLoop {
-            emit: emit,
-            events: events,
-            terminal: Terminal::create(),
-            escape: Escape::create(),
+            emit,
+            events,
+            terminal,
+            escape,
             matches: Matches::from_iter(vec![]),
-            selected: 0,
-            query: "".into(),
+            window,
+            query: Query::new(options.query.as_ref().map_or("", |query| query.as_str())),
+            original: options.query.clone(),
             success: false,
+            execute: false,
+            hidden: HashSet::new(),
             input_thread: Some(input_thread),
-            input_stop: input_stop,
+            input_stop,
             search: None,
-            pool: ThreadPool::new(NUM_THREADS),
+            pool: ThreadPool::new(config.num_threads),
             recent: vec![],
-            completions: None,
+            tags: HashMap::new(),
+            limit: config.match_number,
+            loading: false,
+            config,
         }
     }
 
@@ -85,63 +108,192 @@ impl EventLoop {
         handle.join().expect("Input thread failed");
     }
 
-    fn start_query(&self) {
+    fn start_query(&mut self) {
         if !self.query.is_empty() {
             // only execute queries on non-empty queries
             match self.search {
                 None => {} // do nothing
                 Some(ref base) => {
                     let emit = self.emit.clone();
-                    let query = self.query.clone();
+                    let query = self.query.to_string();
                     let base = base.clone();
+                    let number = self.limit;
+                    self.loading = true;
                     self.pool.execute(move || {
-                        threads::start_query(emit, base, query);
+                        threads::start_query(emit, base, query, number);
                     });
                 }
             }
         }
     }
 
-    pub fn run(&mut self) {
+    fn edit_query<F: FnOnce(&mut Query) -> bool>(&mut self, edit: F) {
+        let before = self.query.to_string();
+        let old_cursor = self.query.cursor_width();
+
+        if !edit(&mut self.query) {
+            self.emit.send(Event::Bell).unwrap();
+            return;
+        }
+
+        let text = self.query.to_string();
+        self.terminal.output_str(self.escape.query_output(&text, old_cursor, self.query.cursor_width()));
+
+        if text != before {
+            self.query_changed();
+        }
+    }
+
+    // returns true when it's time to quit
+    fn action(&mut self, action: Action) -> bool {
+        if let Some(edit) = action.edit() {
+            self.edit_query(|query| query.apply(edit));
+            return false;
+        }
+
+        match action {
+            Action::SelectNext => self.move_selection(|window, total| {
+                if window.selected + 1 < total {
+                    window.selected += 1;
+                }
+            }),
+            Action::SelectPrev => self.move_selection(|window, _| {
+                window.selected = window.selected.saturating_sub(1);
+            }),
+            Action::PageDown => self.move_selection(|window, total| window.page(total, true)),
+            Action::PageUp => self.move_selection(|window, total| window.page(total, false)),
+            Action::Accept | Action::AcceptAndExecute => {
+                self.success = true;
+                self.execute = action == Action::AcceptAndExecute;
+                return true;
+            }
+            Action::Cancel => return true,
+            Action::DeleteEntry => self.delete_entry(),
+            // the input thread switches modes itself
+            _ => {}
+        }
+
+        false
+    }
+
+    fn move_selection<F: FnOnce(&mut Window, usize)>(&mut self, motion: F) {
+        let total = self.matches.len();
+        let before = self.window;
+
+        motion(&mut self.window, total);
+        self.window.scroll(total);
+
+        let size = self.terminal.cols() as usize;
+        let selected = self.window.selected;
+
+        if selected == before.selected {
+            // at the end of what we have, but the search has more coming
+            let waiting = self.matches.more() && selected + 1 >= total;
+
+            if !waiting {
+                self.emit.send(Event::Bell).unwrap();
+            }
+        } else if self.window.offset != before.offset {
+            self.terminal.output_str(self.escape.matches_output(&self.matches, size, &self.window));
+        } else if selected == before.selected + 1 {
+            self.terminal.output_str(self.escape.match_down(&self.matches, size, &self.window));
+        } else if selected + 1 == before.selected {
+            self.terminal.output_str(self.escape.match_up(&self.matches, size, &self.window));
+        } else {
+            self.terminal.output_str(self.escape.matches_output(&self.matches, size, &self.window));
+        }
+
+        self.load_more();
+    }
+
+    // search again for more matches once the selection runs out of them
+    fn load_more(&mut self) {
+        if self.loading {
+            return;
+        }
+
+        if let Some(limit) = next_limit(self.limit, &self.matches, &self.window) {
+            self.limit = limit;
+            debug!("Loading up to {} matches", self.limit);
+            self.start_query();
+        }
+    }
+
+    fn delete_entry(&mut self) {
+        let line = match self.matches.get(self.window.selected) {
+            Some(line) => line.get().clone(),
+            None => {
+                self.emit.send(Event::Bell).unwrap();
+                return;
+            }
+        };
+
+        self.recent.retain(|recent| *recent != line);
+        self.matches.remove(&line);
+        self.hidden.insert(line);
+
+        self.window.selected = self.window.selected.min(self.matches.len().saturating_sub(1));
+        self.window.scroll(self.matches.len());
+
+        let size = self.terminal.cols() as usize;
+        self.terminal.output_str(self.escape.matches_output(&self.matches, size, &self.window));
+    }
+
+    fn redraw(&mut self) {
+        let rows = self.terminal.rows() as usize;
+        let cols = self.terminal.cols() as usize;
+        let query = self.query.to_string();
+
+        self.window.resize(self.escape.height(rows), self.matches.len());
+
+        debug!("Redrawing for {}x{}", cols, rows);
+        self.terminal.output_str(self.escape.redraw(rows, &query, self.query.cursor_width(), &self.matches, cols, &self.window));
+    }
+
+    fn query_changed(&mut self) {
+        self.limit = self.config.match_number;
+
+        if self.query.is_empty() {
+            // nothing to search for, show recent history instead
+            self.loading = false;
+            self.matches = Matches::tagged(self.recent.iter().cloned(), &self.tags);
+            self.window.reset();
+            let size = self.terminal.cols() as usize;
+            self.terminal.output_str(self.escape.matches_output(&self.matches, size, &self.window));
+        } else {
+            self.start_query();
+        }
+    }
+
+    // returns the exit status
+    pub fn run(&mut self) -> i32 {
         // draw the prompt
         let size = self.terminal.rows() as usize;
-        self.terminal.output_str(self.escape.render_prompt(size));
+        self.terminal.output_str(self.escape.render_prompt(size, &self.query.to_string()));
 
         self.terminal.flush();
 
-        for event in self.events.iter() {
+        while let Ok(event) = self.events.recv() {
             match event {
                 Event::CompletionsReady(completions) => {
-                    // put the completions in a refcell
-                    let guard = Arc::new(Mutex::new(completions));
-
-                    // use a lifetime boundary as to clarify the situation to rustc
                     let history_emit = self.emit.clone();
-                    let history_guard = guard.clone();
+                    let history_pool = self.pool.clone();
+                    let history_config = self.config.clone();
 
+                    // changes are saved straight to the file, so these are only for scoring
                     thread::spawn(move || {
-                        let history_completions = if let Ok(completions) = history_guard.try_lock() {
-                            completions
-                        } else {
-                            // the main thread grabbed the lock first, and is probably exiting
-                            // do so ourself
-                            debug!("Completions thread failed to grab completions lock");
-                            return
-                        };
-
-                        threads::read_history(history_completions, history_emit);
+                        threads::read_history(completions, history_emit, history_pool, history_config);
                     });
-
-                    // save the completions so we can use them later
-                    self.completions = Some(guard);
                 }
-                Event::HistoryReady(recent) => {
-                    self.recent = recent;
+                Event::HistoryReady(recent, tags) => {
+                    let hidden = &self.hidden;
+                    self.recent = recent.into_iter().filter(|line| !hidden.contains(line)).collect();
+                    self.tags = tags;
                     if self.query.is_empty() {
-                        self.matches = Matches::from_iter(self.recent.iter().cloned());
-                        self.selected = 0;
+                        self.matches = Matches::tagged(self.recent.iter().cloned(), &self.tags);
+                        self.window.reset();
                         let size = self.terminal.cols() as usize;
-                        self.terminal.output_str(self.escape.matches_output(&self.matches, size, self.selected));
+                        self.terminal.output_str(self.escape.matches_output(&self.matches, size, &self.window));
                     }
                 }
                 Event::SearchReady(base) => {
@@ -149,68 +301,43 @@ impl EventLoop {
                     self.start_query();
                 }
                 Event::Input(chr) => {
-                    self.query.push(chr);
-                    self.terminal.output_str(self.escape.query_output(chr));
-                    self.start_query();
+                    self.edit_query(|query| query.insert(chr));
                 }
-                Event::Match(matches, query) => {
+                Event::Match(matches, query, limit) => {
                     debug!("Got match event: {:?}, {:?}", matches, query);
-                    if query == self.query {
-                        // only draw matches for the current query
-                        self.selected = 0;
-                        self.matches = Matches::from_iter(matches);
-                        let size = self.terminal.cols() as usize;
-                        self.terminal.output_str(self.escape.matches_output(&self.matches, size, self.selected));
-                    }
-                }
-                Event::Quit(success) => {
-                    debug!("Got quit event: {:?}", success);
-                    self.success = success;
-                    break;
-                }
-                Event::KeyDown => {
-                    if self.selected + 1 < self.matches.len() {
-                        self.selected += 1;
+                    if query == self.query.to_string() && limit == self.limit {
+                        // only draw matches for the latest search
+                        self.loading = false;
+
+                        if limit == self.config.match_number {
+                            // a new query, rather than more of the last one
+                            self.window.reset();
+                        }
+
+                        let more = matches.len() >= limit;
+                        let hidden = &self.hidden;
+                        let visible = matches.into_iter().filter(|(line, _)| !hidden.contains(line));
+                        self.matches = Matches::highlighted(visible, &self.tags);
+                        self.matches.set_more(more);
+                        self.window.scroll(self.matches.len());
                         let size = self.terminal.cols() as usize;
-                        self.terminal.output_str(self.escape.match_down(&self.matches, size, self.selected));
-                    } else {
-                        self.emit.send(Event::Bell).unwrap();
+                        self.terminal.output_str(self.escape.matches_output(&self.matches, size, &self.window));
+                        self.load_more();
                     }
                 }
-                Event::KeyUp => {
-                    if self.selected > 0 {
-                        self.selected -= 1;
-                        let size = self.terminal.cols() as usize;
-                        self.terminal.output_str(self.escape.match_up(&self.matches, size, self.selected));
-                    } else {
-                        self.emit.send(Event::Bell).unwrap();
+                Event::Action(action) => {
+                    debug!("Got action: {:?}", action);
+                    if self.action(action) {
+                        break;
                     }
                 }
-                Event::Clear => {
-                    if !self.query.is_empty() {
-                        self.terminal.output_str(self.escape.move_back(self.query.len()));
-                        self.query = "".into();
-                        self.matches = Matches::from_iter(self.recent.iter().cloned());
-                        self.selected = 0;
-                        let size = self.terminal.cols() as usize;
-                        self.terminal.output_str(self.escape.matches_output(&self.matches, size, self.selected));
-                    } else {
-                        self.emit.send(Event::Bell).unwrap();
-                    }
+                Event::Paste(text) => {
+                    // one edit, so the whole paste only starts one search
+                    self.edit_query(|query| query.insert_str(&text));
                 }
-                Event::Backspace => {
-                    if !self.query.is_empty() {
-                        self.query.pop();
-                        self.terminal.output_str(self.escape.move_back(1));
-                        if !self.query.is_empty() {
-                            self.start_query();
-                        } else {
-                            self.matches = Matches::from_iter(self.recent.iter().cloned());
-                            let size = self.terminal.cols() as usize;
-                            self.terminal.output_str(self.escape.matches_output(&self.matches, size, self.selected));
-                        }
-                    } else {
-                        self.emit.send(Event::Bell).unwrap();
+                Event::Resize => {
+                    if self.terminal.update_size() {
+                        self.redraw();
                     }
                 }
                 Event::Bell => {
@@ -225,48 +352,110 @@ impl EventLoop {
         // stop the input thread
         self.stop_threads();
 
+        // leave the cursor after the query so nothing overwrites it
+        let old_cursor = self.query.cursor_width();
+        if self.query.end() {
+            let text = self.query.to_string();
+            self.terminal.output_str(self.escape.query_output(&text, old_cursor, self.query.cursor_width()));
+        }
+
         // draw the best match if it exists
-        self.terminal.output_str(self.escape.best_match_output(&self.matches, self.selected, self.query.is_empty()));
+        if self.success {
+            self.terminal.output_str(self.escape.best_match_output(&self.matches, self.window.selected, self.query.is_empty()));
+        } else {
+            self.terminal.output_str(self.escape.cancel_output());
+        }
 
         debug!("Flushing output");
         self.terminal.flush();
 
         // insert the successful match onto the terminal input buffer
-        if self.success {
-            match self.matches.get(self.selected) {
-                None => debug!("No best match"),
-                Some(m) => {
-                    if let Some(ref completions) = self.completions {
-                        if let Ok(path) = env::current_dir() {
-                            if let Ok(mut completions) = completions.try_lock() {
-                                completions.add_completion(m.get().clone(), path);
-
-                                // try to save them
-                                let mut completions_path = dirs::home_dir().unwrap_or("".into());
-                                completions_path.push(".bis2_completions");
-
-                                trace!("Completions path: {:?}", completions_path);
-
-                                match File::create(completions_path) {
-                                    Ok(mut file) => {
-                                        if let Err(error) = serde_json::to_writer(&mut file, &*completions) {
-                                            warn!("Failed to save completions: {}", error);
-                                        }
-                                    }
-                                    Err(error) => {
-                                        warn!("Failed to open completions file: {}", error);
-                                    }
-                                }
-                            } else {
-                                // the other thread hasn't released completions yet
-                                debug!("Failed to lock completions object");
-                            }
-                        }
-                    }
+        let chosen = if self.success {
+            self.matches.get(self.window.selected).map(|m| m.get().clone())
+        } else {
+            None
+        };
+
+        if self.config.completions && (chosen.is_some() || !self.hidden.is_empty()) {
+            let mut changes: Vec<Change> = self.hidden.iter().map(|line| Change::Forgot(line.clone())).collect();
+
+            if let (Some(line), Ok(path)) = (chosen.as_ref(), env::current_dir()) {
+                changes.push(Change::Chosen(line.clone(), path));
+            }
+
+            let result = Store::home().record(changes, &self.config.retention);
 
-                    self.terminal.insert_input(m.get());
+            if let Err(error) = result {
+                warn!("Failed to save completions: {}", error);
+            }
+        }
+
+        match chosen {
+            None => {
+                debug!("No best match");
+
+                // hand the shell back the line it gave us
+                if let Some(ref original) = self.original {
+                    self.terminal.insert_input(original);
+                }
+
+                0
+            }
+            Some(line) => {
+                self.terminal.insert_input(line);
+
+                if self.execute {
+                    EXECUTE_STATUS
+                } else {
+                    0
                 }
             }
         }
     }
 }
+
+// a bigger search is only worth it when the selection is on the last match we have,
+// the first page always fills the window so it can't be judged by the window alone
+fn next_limit(limit: usize, matches: &Matches, window: &Window) -> Option<usize> {
+    if matches.more() && window.selected + 1 >= matches.len() {
+        Some(limit * 2)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(total: usize) -> Matches {
+        let mut matches = Matches::from_iter((0..total).map(|idx| format!("match {}", idx)));
+        matches.set_more(true);
+        matches
+    }
+
+    #[test]
+    fn first_page_loads_no_more() {
+        let matches = page(10);
+        let window = Window::new(10);
+
+        assert_eq!(next_limit(10, &matches, &window), None);
+    }
+
+    #[test]
+    fn last_match_loads_more() {
+        let matches = page(10);
+        let mut window = Window::new(10);
+
+        window.selected = 8;
+        assert_eq!(next_limit(10, &matches, &window), None);
+
+        window.selected = 9;
+        assert_eq!(next_limit(10, &matches, &window), Some(20));
+
+        // unless that's all there is
+        let mut matches = matches;
+        matches.set_more(false);
+        assert_eq!(next_limit(10, &matches, &window), None);
+    }
+}
@@ -0,0 +1,692 @@
+// Copyright 2015 Jerome Rasky <jerome@rasky.co>
+//
+// Licensed under the Apache License, version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     <http://www.apache.org/licenses/LICENSE-2.0>
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
+// License for the specific language concerning governing permissions and
+// limitations under the License.
+use std::io::prelude::*;
+
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::fs::File;
+
+use std::io;
+
+use dirs;
+
+// zsh stores some bytes in history "metafied": META followed by the byte xor 32
+const ZSH_META: u8 = 0x83;
+
+// fish history is a restricted YAML subset with fixed indentation
+const FISH_CMD: &str = "- cmd: ";
+const FISH_WHEN: &str = "  when: ";
+const FISH_PATHS: &str = "  paths:";
+const FISH_PATH: &str = "    - ";
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum HistoryFormat {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct HistoryEntry {
+    pub line: String,
+    // seconds since the epoch
+    pub timestamp: Option<u64>,
+    // seconds the command ran for
+    pub duration: Option<u64>,
+    // files the command referred to, as recorded by fish
+    pub paths: Vec<String>,
+    // index of the HistorySource this came from
+    pub source: usize,
+}
+
+// a line from any number of runs in any number of sources
+#[derive(PartialEq, Clone, Debug)]
+pub struct HistoryLine {
+    pub line: String,
+    // indices of the sources it was run from, lowest first
+    pub sources: Vec<usize>,
+    // the files its latest run referred to
+    pub paths: Vec<String>,
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct HistorySource {
+    pub path: PathBuf,
+    // detected from the contents when not given
+    pub format: Option<HistoryFormat>,
+}
+
+impl HistoryFormat {
+    pub fn from_name(name: &str) -> Option<HistoryFormat> {
+        match name {
+            "bash" => Some(HistoryFormat::Bash),
+            "zsh" => Some(HistoryFormat::Zsh),
+            "fish" => Some(HistoryFormat::Fish),
+            _ => None,
+        }
+    }
+}
+
+impl HistorySource {
+    pub fn new<P: Into<PathBuf>>(path: P) -> HistorySource {
+        HistorySource {
+            path: path.into(),
+            format: None,
+        }
+    }
+
+    // parse "path" or "format=path"
+    pub fn parse(spec: &str) -> HistorySource {
+        if let Some(split) = spec.find('=') {
+            if let Some(format) = HistoryFormat::from_name(&spec[..split]) {
+                return HistorySource {
+                    path: expand_home(&spec[split + 1..]),
+                    format: Some(format),
+                };
+            }
+        }
+
+        HistorySource::new(expand_home(spec))
+    }
+
+    // short name shown next to matches
+    pub fn tag(&self) -> String {
+        self.path.file_name()
+            .map(|name| name.to_string_lossy().trim_start_matches('.').to_string())
+            .unwrap_or_else(|| self.path.to_string_lossy().into_owned())
+    }
+
+    pub fn load(&self, index: usize) -> io::Result<Vec<HistoryEntry>> {
+        let mut entries = read_entries(&self.path, self.format)?;
+
+        // concurrent shells append out of order, so trust timestamps over file position
+        sort_by_time(&mut entries);
+
+        for entry in entries.iter_mut() {
+            entry.source = index;
+        }
+
+        Ok(entries)
+    }
+}
+
+impl HistoryEntry {
+    pub fn new(line: String) -> HistoryEntry {
+        HistoryEntry {
+            line,
+            timestamp: None,
+            duration: None,
+            paths: vec![],
+            source: 0,
+        }
+    }
+}
+
+// config files don't go through the shell, so expand "~/" ourselves
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => path.into(),
+    }
+}
+
+pub fn read_entries<P: AsRef<Path>>(path: P, format: Option<HistoryFormat>) -> io::Result<Vec<HistoryEntry>> {
+    let path = path.as_ref();
+    let mut contents = vec![];
+
+    File::open(path)?.read_to_end(&mut contents)?;
+
+    let format = format.unwrap_or_else(|| detect_format(path, &contents));
+
+    debug!("History format: {:?}", format);
+
+    Ok(parse_entries(format, &contents))
+}
+
+pub fn detect_format(path: &Path, contents: &[u8]) -> HistoryFormat {
+    // extended history is unambiguous, so look at the contents first
+    let first_line = contents.split(|&b| b == b'\n')
+        .find(|line| !line.is_empty());
+
+    if let Some(line) = first_line {
+        let line = String::from_utf8_lossy(line);
+
+        if parse_zsh_extended(&line).is_some() {
+            return HistoryFormat::Zsh;
+        } else if line.starts_with(FISH_CMD) {
+            return HistoryFormat::Fish;
+        }
+    }
+
+    // plain zsh history looks just like bash history, so fall back on the name
+    let name = path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    if name.contains("zsh") {
+        HistoryFormat::Zsh
+    } else if name.contains("fish") {
+        HistoryFormat::Fish
+    } else {
+        HistoryFormat::Bash
+    }
+}
+
+pub fn parse_entries(format: HistoryFormat, contents: &[u8]) -> Vec<HistoryEntry> {
+    match format {
+        HistoryFormat::Bash => parse_bash(contents),
+        HistoryFormat::Zsh => parse_zsh(contents),
+        HistoryFormat::Fish => parse_fish(contents),
+    }
+}
+
+fn parse_bash(contents: &[u8]) -> Vec<HistoryEntry> {
+    let mut entries = vec![];
+    let mut current: Option<HistoryEntry> = None;
+    let mut following: Vec<&str> = vec![];
+    let contents = String::from_utf8_lossy(contents);
+
+    for line in contents.lines() {
+        if let Some(timestamp) = parse_bash_timestamp(line) {
+            // with HISTTIMEFORMAT set, a "#<epoch>" comment starts each command,
+            // and the lines between two timestamps make up one multi-line command
+            if let Some(ref mut entry) = current {
+                for line in following.drain(..) {
+                    entry.line.push('\n');
+                    entry.line.push_str(line);
+                }
+            }
+            entries.extend(current.take());
+
+            let mut entry = HistoryEntry::new(String::new());
+            entry.timestamp = Some(timestamp);
+            current = Some(entry);
+        } else if line.is_empty() {
+            continue;
+        } else if let Some(ref mut entry) = current {
+            if entry.line.is_empty() {
+                entry.line.push_str(line);
+            } else {
+                following.push(line);
+            }
+        } else {
+            entries.push(HistoryEntry::new(line.into()));
+        }
+    }
+
+    // without a timestamp after them, the lines that follow the last timestamped
+    // command were written with HISTTIMEFORMAT unset, one command per line
+    entries.extend(current);
+    entries.extend(following.into_iter().map(|line| HistoryEntry::new(line.into())));
+
+    entries.into_iter()
+        .filter(|entry| !entry.line.is_empty())
+        .collect()
+}
+
+fn parse_bash_timestamp(line: &str) -> Option<u64> {
+    let digits = line.strip_prefix('#')?;
+
+    if !digits.is_empty() && digits.chars().all(|chr| chr.is_ascii_digit()) {
+        digits.parse().ok()
+    } else {
+        None
+    }
+}
+
+// orders entries by when they ran, keeping file order for untimestamped entries
+pub fn sort_by_time(entries: &mut Vec<HistoryEntry>) {
+    let mut last = 0;
+
+    let mut keyed: Vec<(u64, HistoryEntry)> = entries.drain(..).map(|entry| {
+        if let Some(timestamp) = entry.timestamp {
+            last = timestamp;
+        }
+
+        (last, entry)
+    }).collect();
+
+    // stable, so commands from the same second stay in file order
+    keyed.sort_by_key(|&(key, _)| key);
+
+    entries.extend(keyed.into_iter().map(|(_, entry)| entry));
+}
+
+// collapses entries sorted by time into their lines, least recently run first
+pub fn dedupe(entries: Vec<HistoryEntry>) -> Vec<HistoryLine> {
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    let mut lines: Vec<HistoryLine> = vec![];
+
+    // newest first, so each line is placed by its latest run
+    for entry in entries.into_iter().rev() {
+        if let Some(&index) = seen.get(&entry.line) {
+            let sources = &mut lines[index].sources;
+
+            if !sources.contains(&entry.source) {
+                sources.push(entry.source);
+            }
+
+            continue;
+        }
+
+        seen.insert(entry.line.clone(), lines.len());
+        lines.push(HistoryLine {
+            line: entry.line,
+            sources: vec![entry.source],
+            paths: entry.paths,
+        });
+    }
+
+    for line in lines.iter_mut() {
+        line.sources.sort();
+    }
+
+    lines.reverse();
+    lines
+}
+
+// names the files each line came from, when there's more than one it could be
+pub fn tags(lines: &[HistoryLine], sources: &[HistorySource]) -> HashMap<String, String> {
+    if sources.len() < 2 {
+        return HashMap::new();
+    }
+
+    lines.iter().map(|line| {
+        let names: Vec<String> = line.sources.iter().map(|&index| sources[index].tag()).collect();
+        (line.line.clone(), names.join(","))
+    }).collect()
+}
+
+fn parse_zsh(contents: &[u8]) -> Vec<HistoryEntry> {
+    let contents = unmetafy(contents);
+    let mut entries = vec![];
+    let mut pending: Option<String> = None;
+
+    for line in String::from_utf8_lossy(&contents).lines() {
+        let mut current = match pending.take() {
+            Some(mut command) => {
+                // continuation of a multi-line command
+                command.push('\n');
+                command.push_str(line);
+                command
+            }
+            None => line.into(),
+        };
+
+        if current.ends_with('\\') {
+            // zsh escapes embedded newlines with a trailing backslash
+            current.pop();
+            pending = Some(current);
+            continue;
+        }
+
+        if let Some(entry) = zsh_entry(current) {
+            entries.push(entry);
+        }
+    }
+
+    if let Some(command) = pending {
+        // the file ended partway through a command
+        if let Some(entry) = zsh_entry(command) {
+            entries.push(entry);
+        }
+    }
+
+    entries
+}
+
+fn zsh_entry(raw: String) -> Option<HistoryEntry> {
+    let entry = match parse_zsh_extended(&raw) {
+        Some((timestamp, duration, command)) => HistoryEntry {
+            line: command.into(),
+            timestamp: Some(timestamp),
+            duration: Some(duration),
+            paths: vec![],
+            source: 0,
+        },
+        None => HistoryEntry::new(raw),
+    };
+
+    if entry.line.is_empty() {
+        None
+    } else {
+        Some(entry)
+    }
+}
+
+// parse ": <start>:<elapsed>;<command>"
+fn parse_zsh_extended(line: &str) -> Option<(u64, u64, &str)> {
+    if !line.starts_with(": ") {
+        return None;
+    }
+
+    let rest = &line[2..];
+    let split = rest.find(';')?;
+    let (meta, command) = (&rest[..split], &rest[split + 1..]);
+    let colon = meta.find(':')?;
+
+    let timestamp = meta[..colon].trim().parse().ok()?;
+    let duration = meta[colon + 1..].trim().parse().ok()?;
+
+    Some((timestamp, duration, command))
+}
+
+fn unmetafy(contents: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(contents.len());
+    let mut bytes = contents.iter();
+
+    while let Some(&byte) = bytes.next() {
+        if byte == ZSH_META {
+            if let Some(&next) = bytes.next() {
+                result.push(next ^ 32);
+            }
+        } else {
+            result.push(byte);
+        }
+    }
+
+    result
+}
+
+fn parse_fish(contents: &[u8]) -> Vec<HistoryEntry> {
+    let mut entries = vec![];
+    let mut current: Option<HistoryEntry> = None;
+    let mut in_paths = false;
+
+    for line in String::from_utf8_lossy(contents).lines() {
+        if let Some(command) = line.strip_prefix(FISH_CMD) {
+            entries.extend(current.take());
+            current = Some(HistoryEntry::new(unescape_fish(command)));
+            in_paths = false;
+        } else if let Some(ref mut entry) = current {
+            if let Some(when) = line.strip_prefix(FISH_WHEN) {
+                entry.timestamp = when.trim().parse().ok();
+                in_paths = false;
+            } else if line == FISH_PATHS {
+                in_paths = true;
+            } else if let (true, Some(path)) = (in_paths, line.strip_prefix(FISH_PATH)) {
+                entry.paths.push(unescape_fish(path));
+            } else {
+                trace!("Ignoring fish history line: {:?}", line);
+                in_paths = false;
+            }
+        }
+    }
+
+    entries.extend(current);
+
+    entries.into_iter()
+        .filter(|entry| !entry.line.is_empty())
+        .collect()
+}
+
+// fish escapes backslashes and newlines so every command fits on one line
+fn unescape_fish(escaped: &str) -> String {
+    let mut result = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+
+    while let Some(chr) = chars.next() {
+        if chr != '\\' {
+            result.push(chr);
+            continue;
+        }
+
+        match chars.next() {
+            Some('\\') => result.push('\\'),
+            Some('n') => result.push('\n'),
+            Some(other) => {
+                result.push('\\');
+                result.push(other);
+            }
+            None => result.push('\\'),
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(entries: &[HistoryEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.line.as_str()).collect()
+    }
+
+    #[test]
+    fn zsh_plain() {
+        let entries = parse_entries(HistoryFormat::Zsh, b"ls\ncd /tmp\n\nmake\n");
+
+        assert_eq!(lines(&entries), vec!["ls", "cd /tmp", "make"]);
+        assert!(entries.iter().all(|entry| entry.timestamp.is_none()));
+    }
+
+    #[test]
+    fn zsh_extended() {
+        let entries = parse_entries(HistoryFormat::Zsh, b": 1500000000:0;ls -la\n: 1500000010:12;make test\n");
+
+        assert_eq!(lines(&entries), vec!["ls -la", "make test"]);
+        assert_eq!(entries[0].timestamp, Some(1500000000));
+        assert_eq!(entries[0].duration, Some(0));
+        assert_eq!(entries[1].timestamp, Some(1500000010));
+        assert_eq!(entries[1].duration, Some(12));
+    }
+
+    #[test]
+    fn zsh_extended_keeps_semicolons() {
+        let entries = parse_entries(HistoryFormat::Zsh, b": 1500000000:0;a; b; c\n");
+
+        assert_eq!(lines(&entries), vec!["a; b; c"]);
+    }
+
+    #[test]
+    fn zsh_continuation() {
+        let contents = b": 1500000000:0;for x in a b\\\ndo\\\n  echo $x\\\ndone\n: 1500000001:0;ls\n";
+        let entries = parse_entries(HistoryFormat::Zsh, contents);
+
+        assert_eq!(lines(&entries), vec!["for x in a b\ndo\n  echo $x\ndone", "ls"]);
+        assert_eq!(entries[0].timestamp, Some(1500000000));
+    }
+
+    #[test]
+    fn zsh_continuation_at_end_of_file() {
+        let entries = parse_entries(HistoryFormat::Zsh, b": 1500000000:0;echo a\\\n");
+
+        assert_eq!(lines(&entries), vec!["echo a"]);
+    }
+
+    #[test]
+    fn zsh_metafied() {
+        // "ă" is c4 83, and zsh writes the 0x83 as META, 0x83 ^ 32
+        let entries = parse_entries(HistoryFormat::Zsh, b": 1500000000:0;echo \xc4\x83\xa3\n");
+
+        assert_eq!(lines(&entries), vec!["echo \u{103}"]);
+    }
+
+    #[test]
+    fn zsh_not_extended() {
+        assert_eq!(parse_zsh_extended(": 12:3;ls"), Some((12, 3, "ls")));
+        assert_eq!(parse_zsh_extended(": not:a;timestamp"), None);
+        assert_eq!(parse_zsh_extended(":12:3;ls"), None);
+        assert_eq!(parse_zsh_extended(": 12;ls"), None);
+    }
+
+    #[test]
+    fn detect_by_contents() {
+        let path = Path::new("/home/user/history");
+
+        assert_eq!(detect_format(path, b"\n: 1500000000:0;ls\n"), HistoryFormat::Zsh);
+        assert_eq!(detect_format(path, b"ls\ncd\n"), HistoryFormat::Bash);
+        assert_eq!(detect_format(path, b""), HistoryFormat::Bash);
+    }
+
+    #[test]
+    fn detect_by_name() {
+        assert_eq!(detect_format(Path::new("/home/user/.zsh_history"), b"ls\n"), HistoryFormat::Zsh);
+        assert_eq!(detect_format(Path::new("/home/user/.bash_history"), b"ls\n"), HistoryFormat::Bash);
+    }
+
+    #[test]
+    fn bash_plain() {
+        let entries = parse_entries(HistoryFormat::Bash, b"ls\n\ncd /tmp\n#not a timestamp\n");
+
+        assert_eq!(lines(&entries), vec!["ls", "cd /tmp", "#not a timestamp"]);
+        assert!(entries.iter().all(|entry| entry.timestamp.is_none()));
+    }
+
+    #[test]
+    fn bash_timestamps() {
+        let entries = parse_entries(HistoryFormat::Bash, b"#1500000000\nls\n#1500000010\nmake\n");
+
+        assert_eq!(lines(&entries), vec!["ls", "make"]);
+        assert_eq!(entries[0].timestamp, Some(1500000000));
+        assert_eq!(entries[1].timestamp, Some(1500000010));
+    }
+
+    #[test]
+    fn bash_multi_line() {
+        let contents = b"#1500000000\nfor x in a b\ndo echo $x\ndone\n#1500000010\nls\n";
+        let entries = parse_entries(HistoryFormat::Bash, contents);
+
+        assert_eq!(lines(&entries), vec!["for x in a b\ndo echo $x\ndone", "ls"]);
+        assert_eq!(entries[0].timestamp, Some(1500000000));
+    }
+
+    #[test]
+    fn bash_mixed_timestamps() {
+        let contents = b"ls\n#1500000000\nmake\ncd /tmp\nvim notes\n#1500000010\ngit status\npwd\nexit\n";
+        let entries = parse_entries(HistoryFormat::Bash, contents);
+
+        assert_eq!(lines(&entries), vec!["ls", "make\ncd /tmp\nvim notes", "git status", "pwd", "exit"]);
+        assert_eq!(entries[0].timestamp, None);
+        assert_eq!(entries[2].timestamp, Some(1500000010));
+        assert_eq!(entries[3].timestamp, None);
+        assert_eq!(entries[4].timestamp, None);
+    }
+
+    #[test]
+    fn bash_timestamp_without_command() {
+        let entries = parse_entries(HistoryFormat::Bash, b"#1500000000\n#1500000010\nls\n");
+
+        assert_eq!(lines(&entries), vec!["ls"]);
+        assert_eq!(entries[0].timestamp, Some(1500000010));
+    }
+
+    #[test]
+    fn sorted_by_time() {
+        let mut entries = parse_entries(HistoryFormat::Bash, b"#20\nb\n#10\na\n#20\nc\n");
+        sort_by_time(&mut entries);
+
+        assert_eq!(lines(&entries), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn fish_entries() {
+        let contents = b"- cmd: ls -la\n  when: 1500000000\n- cmd: vim notes.txt\n  when: 1500000010\n  paths:\n    - notes.txt\n    - other\\ file\n";
+        let entries = parse_entries(HistoryFormat::Fish, contents);
+
+        assert_eq!(lines(&entries), vec!["ls -la", "vim notes.txt"]);
+        assert_eq!(entries[0].timestamp, Some(1500000000));
+        assert!(entries[0].paths.is_empty());
+        assert_eq!(entries[1].timestamp, Some(1500000010));
+        assert_eq!(entries[1].paths, vec!["notes.txt", "other\\ file"]);
+    }
+
+    #[test]
+    fn fish_unescapes() {
+        let entries = parse_entries(HistoryFormat::Fish, b"- cmd: echo a\\nb \\\\ \\t\n  when: 1\n");
+
+        assert_eq!(lines(&entries), vec!["echo a\nb \\ \\t"]);
+    }
+
+    #[test]
+    fn fish_ignores_other_keys() {
+        let contents = b"- cmd: make\n  when: 5\n  paths:\n    - Makefile\n  other: thing\n    - not a path\n";
+        let entries = parse_entries(HistoryFormat::Fish, contents);
+
+        assert_eq!(entries[0].paths, vec!["Makefile"]);
+    }
+
+    #[test]
+    fn fish_bad_timestamp() {
+        let entries = parse_entries(HistoryFormat::Fish, b"- cmd: ls\n  when: soon\n- cmd: \n  when: 2\n");
+
+        assert_eq!(lines(&entries), vec!["ls"]);
+        assert_eq!(entries[0].timestamp, None);
+    }
+
+    #[test]
+    fn detect_fish() {
+        assert_eq!(detect_format(Path::new("/tmp/history"), b"- cmd: ls\n  when: 1\n"), HistoryFormat::Fish);
+        assert_eq!(detect_format(Path::new("/home/user/.local/share/fish/fish_history"), b""), HistoryFormat::Fish);
+    }
+
+    fn entry(line: &str, source: usize, paths: &[&str]) -> HistoryEntry {
+        HistoryEntry {
+            source,
+            paths: paths.iter().map(|path| path.to_string()).collect(),
+            ..HistoryEntry::new(line.into())
+        }
+    }
+
+    #[test]
+    fn dedupe_by_last_run() {
+        let merged = dedupe(vec![
+            entry("make", 0, &["Makefile"]),
+            entry("ls", 0, &[]),
+            entry("make", 1, &["src/Makefile"]),
+            entry("cd /tmp", 1, &[]),
+            entry("make", 0, &[]),
+        ]);
+
+        let lines: Vec<&str> = merged.iter().map(|line| line.line.as_str()).collect();
+        assert_eq!(lines, vec!["ls", "cd /tmp", "make"]);
+
+        // sources in order however they were run, paths from the latest run
+        assert_eq!(merged[2].sources, vec![0, 1]);
+        assert_eq!(merged[2].paths, Vec::<String>::new());
+        assert_eq!(merged[1].sources, vec![1]);
+    }
+
+    #[test]
+    fn dedupe_keeps_latest_paths() {
+        let merged = dedupe(vec![
+            entry("vim notes", 0, &["notes"]),
+            entry("vim notes", 0, &["notes", "todo"]),
+        ]);
+
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].paths, vec!["notes", "todo"]);
+        assert_eq!(merged[0].sources, vec![0]);
+    }
+
+    #[test]
+    fn tags_by_source() {
+        let sources = vec![HistorySource::new("/home/user/.bash_history"), HistorySource::new("/tmp/zsh_history")];
+        let merged = dedupe(vec![
+            entry("ls", 1, &[]),
+            entry("make", 0, &[]),
+            entry("ls", 0, &[]),
+        ]);
+
+        let tagged = tags(&merged, &sources);
+
+        assert_eq!(tagged.len(), 2);
+        assert_eq!(tagged["ls"], "bash_history,zsh_history");
+        assert_eq!(tagged["make"], "bash_history");
+
+        // a single source has nothing to tell apart
+        assert!(tags(&merged, &sources[..1]).is_empty());
+    }
+}
@@ -0,0 +1,591 @@
+// Copyright 2015 Jerome Rasky <jerome@rasky.co>
+//
+// Licensed under the Apache License, version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     <http://www.apache.org/licenses/LICENSE-2.0>
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
+// License for the specific language concerning governing permissions and
+// limitations under the License.
+
+// the completions on disk, mapped in and looked up a line at a time
+//
+// everything is little endian:
+//
+//   magic "BIS2", u32 format version, u64 number of lines, u64 generation
+//   per line, sorted by hash: u64 hash of the line, u64 offset of its record
+//   per line, a record:
+//     u32 length, line
+//     u32 number of directories, each u32 length, path, f32 count, u64 time
+//     u8 1 if runs follow, then u32 count, u32 failures, f64 seconds, u64 time,
+//       and u8 1 if a u32 length and directory follow
+use std::ffi::OsStr;
+use std::fs::File;
+use std::path::Path;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+
+use std::convert::TryInto;
+use std::io;
+use std::ptr;
+use std::slice;
+use std::str;
+
+use libc;
+
+use types::{Completions, Retention, Runs, Visit};
+use types;
+use store::FormatError;
+
+const MAGIC: &[u8] = b"BIS2";
+
+// bump this whenever the layout changes, and teach check to read the old one,
+// since other versions are refused rather than misread
+pub const FORMAT_VERSION: u32 = 1;
+
+const HEADER_LENGTH: usize = 24;
+const SLOT_LENGTH: usize = 16;
+
+// a read-only view of a file, which stays valid if the file is replaced
+#[derive(Debug)]
+struct Mapping {
+    data: *const u8,
+    length: usize,
+}
+
+// nothing writes through the mapping, so it can move to the history thread
+unsafe impl Send for Mapping {}
+
+impl Mapping {
+    fn new(file: &File) -> io::Result<Mapping> {
+        let length = file.metadata()?.len() as usize;
+
+        if length == 0 {
+            // mmap refuses empty files
+            return Ok(Mapping { data: ptr::null(), length });
+        }
+
+        let data = unsafe {
+            libc::mmap(ptr::null_mut(), length, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), 0)
+        };
+
+        if data == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Mapping { data: data as *const u8, length })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        if self.data.is_null() {
+            &[]
+        } else {
+            unsafe { slice::from_raw_parts(self.data, self.length) }
+        }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if !self.data.is_null() {
+            unsafe {
+                libc::munmap(self.data as *mut libc::c_void, self.length);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Index {
+    mapping: Mapping,
+    count: usize,
+    // bumped by every compaction, so a journal is only replayed onto the index it was written for
+    generation: u64,
+}
+
+// what a record says, borrowed from the mapping where it can be
+struct Record<'a> {
+    line: &'a str,
+    paths: Vec<(&'a Path, f32, u64)>,
+    runs: Option<Runs>,
+}
+
+impl<'a> Record<'a> {
+    fn owned(self) -> (Vec<Visit>, Option<Runs>) {
+        (self.paths.iter().map(|&(path, count, time)| (path.to_path_buf(), count, time)).collect(), self.runs)
+    }
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8], position: usize) -> Reader<'a> {
+        Reader { data, position }
+    }
+
+    pub fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let end = self.position.checked_add(length)
+            .filter(|&end| end <= self.data.len())
+            .ok_or_else(|| format!("truncated at {}", self.position))?;
+
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn f32(&mut self) -> Result<f32, String> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    pub fn f64(&mut self) -> Result<f64, String> {
+        Ok(f64::from_bits(self.u64()?))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], String> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+
+    pub fn path(&mut self) -> Result<&'a Path, String> {
+        Ok(Path::new(OsStr::from_bytes(self.bytes()?)))
+    }
+}
+
+// fnv-1a, which unlike the standard hasher is the same in every build
+fn hash(line: &str) -> u64 {
+    line.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+impl Index {
+    pub fn open(path: &Path) -> io::Result<Result<Index, FormatError>> {
+        let mapping = Mapping::new(&File::open(path)?)?;
+
+        Ok(Index::check(mapping))
+    }
+
+    fn check(mapping: Mapping) -> Result<Index, FormatError> {
+        let (count, generation) = {
+            let mut reader = Reader::new(mapping.bytes(), 0);
+
+            if reader.take(MAGIC.len()).map_err(FormatError::Corrupt)? != MAGIC {
+                return Err(FormatError::Corrupt("not a bis2 store".into()));
+            }
+
+            let version = reader.u32().map_err(FormatError::Corrupt)?;
+
+            match version {
+                FORMAT_VERSION => {}
+                newer if newer > FORMAT_VERSION => return Err(FormatError::Newer(newer as u64)),
+                older => return Err(FormatError::Corrupt(format!("unsupported store version {}", older))),
+            }
+
+            let count = reader.u64().map_err(FormatError::Corrupt)? as usize;
+
+            (count, reader.u64().map_err(FormatError::Corrupt)?)
+        };
+
+        let slots = count.checked_mul(SLOT_LENGTH).and_then(|length| length.checked_add(HEADER_LENGTH));
+
+        match slots {
+            Some(end) if end <= mapping.length => Ok(Index { mapping, count, generation }),
+            _ => Err(FormatError::Corrupt(format!("{} lines don't fit", count))),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    // (hash, offset) of the nth line
+    fn slot(&self, nth: usize) -> (u64, usize) {
+        let mut reader = Reader::new(self.mapping.bytes(), HEADER_LENGTH + nth * SLOT_LENGTH);
+
+        // check made sure the slots are all there
+        (reader.u64().unwrap(), reader.u64().unwrap() as usize)
+    }
+
+    fn record(&self, offset: usize) -> Result<Record<'_>, String> {
+        let mut reader = Reader::new(self.mapping.bytes(), offset);
+
+        let line = str::from_utf8(reader.bytes()?).map_err(|error| error.to_string())?;
+
+        let directories = reader.u32()?;
+        let mut paths = Vec::new();
+
+        for _ in 0..directories {
+            paths.push((reader.path()?, reader.f32()?, reader.u64()?));
+        }
+
+        let runs = if reader.u8()? == 1 {
+            let count = reader.u32()?;
+            let failures = reader.u32()?;
+            let seconds = reader.f64()?;
+            let time = reader.u64()?;
+
+            let directory = if reader.u8()? == 1 {
+                Some(reader.path()?.to_path_buf())
+            } else {
+                None
+            };
+
+            Some(Runs { count, failures, seconds, directory, time })
+        } else {
+            None
+        };
+
+        Ok(Record { line, paths, runs })
+    }
+
+    fn find(&self, line: &str) -> Option<Record<'_>> {
+        let wanted = hash(line);
+
+        // the first slot that could hold it
+        let (mut low, mut high) = (0, self.count);
+
+        while low < high {
+            let middle = (low + high) / 2;
+
+            if self.slot(middle).0 < wanted {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+
+        for nth in low..self.count {
+            let (hash, offset) = self.slot(nth);
+
+            if hash != wanted {
+                break;
+            }
+
+            match self.record(offset) {
+                Ok(ref record) if record.line != line => {}
+                Ok(record) => return Some(record),
+                Err(error) => {
+                    warn!("Skipping bad completions record: {}", error);
+                }
+            }
+        }
+
+        None
+    }
+
+    pub fn get_score(&self, line: &str, path: &Path, factor: f32, retention: &Retention, now: u64) -> f32 {
+        match self.find(line) {
+            Some(record) => types::directory_score(record.paths.into_iter(), path, retention, now) * factor,
+            None => 0.0,
+        }
+    }
+
+    pub fn failure_rate(&self, line: &str, retention: &Retention, now: u64) -> f32 {
+        self.find(line).and_then(|record| record.runs).map_or(0.0, |runs| runs.failure_rate(retention, now))
+    }
+
+    // one line's record, for when it's about to be changed
+    pub fn get(&self, line: &str) -> Option<(Vec<Visit>, Option<Runs>)> {
+        self.find(line).map(Record::owned)
+    }
+
+    // everything, for when it's about to be changed and written back
+    pub fn load(&self) -> Result<Completions, String> {
+        let mut completions = Completions::new();
+
+        for nth in 0..self.count {
+            let record = self.record(self.slot(nth).1)?;
+            let line = record.line.to_string();
+            let (paths, runs) = record.owned();
+
+            completions.insert(line, paths, runs);
+        }
+
+        Ok(completions)
+    }
+}
+
+pub fn encode(completions: &Completions, generation: u64) -> Vec<u8> {
+    let mut entries: Vec<(u64, &str, &[Visit], Option<&Runs>)> = completions.entries().into_iter()
+        .map(|(line, paths, runs)| (hash(line), line, paths, runs))
+        .collect();
+
+    entries.sort_by_key(|&(hash, line, _, _)| (hash, line));
+
+    let mut records = Vec::new();
+    let mut slots = Vec::with_capacity(entries.len());
+    let start = HEADER_LENGTH + entries.len() * SLOT_LENGTH;
+
+    for (hash, line, paths, runs) in entries {
+        slots.push((hash, start + records.len()));
+
+        put_bytes(&mut records, line.as_bytes());
+        records.extend_from_slice(&(paths.len() as u32).to_le_bytes());
+
+        for &(ref path, count, time) in paths {
+            put_bytes(&mut records, path.as_os_str().as_bytes());
+            records.extend_from_slice(&count.to_bits().to_le_bytes());
+            records.extend_from_slice(&time.to_le_bytes());
+        }
+
+        match runs {
+            Some(runs) => {
+                records.push(1);
+                records.extend_from_slice(&runs.count.to_le_bytes());
+                records.extend_from_slice(&runs.failures.to_le_bytes());
+                records.extend_from_slice(&runs.seconds.to_bits().to_le_bytes());
+                records.extend_from_slice(&runs.time.to_le_bytes());
+
+                match runs.directory {
+                    Some(ref directory) => {
+                        records.push(1);
+                        put_bytes(&mut records, directory.as_os_str().as_bytes());
+                    }
+                    None => records.push(0),
+                }
+            }
+            None => records.push(0),
+        }
+    }
+
+    let mut data = Vec::with_capacity(start + records.len());
+
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    data.extend_from_slice(&(slots.len() as u64).to_le_bytes());
+    data.extend_from_slice(&generation.to_le_bytes());
+
+    for (hash, offset) in slots {
+        data.extend_from_slice(&hash.to_le_bytes());
+        data.extend_from_slice(&(offset as u64).to_le_bytes());
+    }
+
+    data.extend_from_slice(&records);
+    data
+}
+
+pub fn put_bytes(data: &mut Vec<u8>, bytes: &[u8]) {
+    data.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    data.extend_from_slice(bytes);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+    use std::path::PathBuf;
+
+    use std::fs;
+
+    use testing::Directory;
+
+    const NOW: u64 = 1_600_000_000;
+
+    // the index a test reads, written into its own directory
+    fn open(directory: &Directory, data: &[u8]) -> Result<Index, FormatError> {
+        let path = directory.join("completions.db");
+        fs::write(&path, data).unwrap();
+        Index::open(&path).unwrap()
+    }
+
+    fn sample() -> Completions {
+        let mut completions = Completions::new();
+        let odd = PathBuf::from(OsString::from_vec(b"/tmp/not\xffutf8".to_vec()));
+
+        completions.insert("make".into(), vec![("/src".into(), 2.5, NOW), (odd, 1.0, NOW - 10)], None);
+        completions.insert("false".into(), vec![], Some(Runs {
+            count: 4,
+            failures: 3,
+            seconds: 0.25,
+            directory: None,
+            time: NOW,
+        }));
+        completions.insert("cargo test".into(), vec![("/src/bis2".into(), 1.0, NOW)], Some(Runs {
+            count: 1,
+            failures: 0,
+            seconds: 30.0,
+            directory: Some("/src/bis2".into()),
+            time: NOW,
+        }));
+
+        completions
+    }
+
+    // slots pointing at the records for these lines, in this order, all with the given hash
+    fn slots_for(data: &[u8], lines: &[&str], hash: u64) -> Vec<u8> {
+        let count = u64::from_le_bytes(data[8..16].try_into().unwrap()) as usize;
+        let mut data = data.to_vec();
+
+        let offsets: Vec<(String, u64)> = (0..count).map(|nth| {
+            let mut reader = Reader::new(&data, HEADER_LENGTH + nth * SLOT_LENGTH + 8);
+            let offset = reader.u64().unwrap();
+            let line = str::from_utf8(Reader::new(&data, offset as usize).bytes().unwrap()).unwrap().to_string();
+            (line, offset)
+        }).collect();
+
+        for (nth, line) in lines.iter().enumerate() {
+            let offset = offsets.iter().find(|&(known, _)| known == line).unwrap().1;
+            let start = HEADER_LENGTH + nth * SLOT_LENGTH;
+
+            data[start..start + 8].copy_from_slice(&hash.to_le_bytes());
+            data[start + 8..start + 16].copy_from_slice(&offset.to_le_bytes());
+        }
+
+        data
+    }
+
+    #[test]
+    fn round_trip() {
+        let completions = sample();
+        let directory = Directory::new("round-trip");
+        let index = open(&directory, &encode(&completions, 1)).unwrap();
+
+        assert_eq!(index.len(), 3);
+        assert_eq!(index.load().unwrap(), completions);
+    }
+
+    #[test]
+    fn generation() {
+        let directory = Directory::new("generation");
+
+        assert_eq!(open(&directory, &encode(&sample(), 7)).unwrap().generation(), 7);
+    }
+
+    #[test]
+    fn lookups() {
+        let completions = sample();
+        let retention = Retention::default();
+        let directory = Directory::new("lookups");
+        let index = open(&directory, &encode(&completions, 1)).unwrap();
+
+        for &(line, _, _) in completions.entries().iter() {
+            let here = Path::new("/src/bis2");
+
+            assert_eq!(index.get_score(line, here, 10.0, &retention, NOW),
+                       completions.get_score(line, here, 10.0, &retention, NOW), "{:?}", line);
+            assert_eq!(index.failure_rate(line, &retention, NOW), completions.failure_rate(line, &retention, NOW), "{:?}", line);
+        }
+
+        assert_eq!(index.failure_rate("false", &retention, NOW), 0.75);
+        assert!(index.find("unknown").is_none());
+        assert!(index.find("").is_none());
+    }
+
+    #[test]
+    fn empty() {
+        let directory = Directory::new("empty");
+        let index = open(&directory, &encode(&Completions::new(), 1)).unwrap();
+
+        assert_eq!(index.len(), 0);
+        assert_eq!(index.load().unwrap(), Completions::new());
+        assert!(index.find("make").is_none());
+    }
+
+    #[test]
+    fn collisions() {
+        let data = encode(&sample(), 1);
+        let shared = hash("make");
+
+        let directory = Directory::new("collisions");
+        let index = open(&directory, &slots_for(&data, &["false", "cargo test", "make"], shared)).unwrap();
+
+        assert_eq!(index.find("make").unwrap().line, "make");
+        // the others' hashes don't match their slots any more
+        assert!(index.find("false").is_none());
+
+        let directory = Directory::new("collisions-first");
+        let index = open(&directory, &slots_for(&data, &["make", "false", "cargo test"], shared)).unwrap();
+
+        assert_eq!(index.find("make").unwrap().line, "make");
+    }
+
+    #[test]
+    fn out_of_range_offset() {
+        let mut data = encode(&sample(), 1);
+        let length = data.len() as u64;
+
+        // point the first slot past the end
+        data[HEADER_LENGTH + 8..HEADER_LENGTH + 16].copy_from_slice(&(length + 100).to_le_bytes());
+
+        let directory = Directory::new("out-of-range");
+        let index = open(&directory, &data).unwrap();
+
+        assert!(index.load().is_err());
+        assert_eq!(sample().entries().iter().filter(|&&(line, _, _)| index.find(line).is_some()).count(), 2);
+    }
+
+    #[test]
+    fn truncated_records() {
+        let data = encode(&sample(), 1);
+        let directory = Directory::new("truncated-records");
+        let index = open(&directory, &data[..data.len() - 4]).unwrap();
+
+        assert!(index.load().is_err());
+    }
+
+    #[test]
+    fn truncated_slots() {
+        let data = encode(&sample(), 1);
+        let directory = Directory::new("truncated-slots");
+
+        match open(&directory, &data[..HEADER_LENGTH + SLOT_LENGTH]) {
+            Err(FormatError::Corrupt(_)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn not_an_index() {
+        for (name, data) in [("empty-file", &b""[..]), ("short", &b"BIS"[..]), ("json", &b"{\"info\":{}}"[..])].iter() {
+            match open(&Directory::new(name), data) {
+                Err(FormatError::Corrupt(_)) => {}
+                other => panic!("unexpected {:?} for {}", other, name),
+            }
+        }
+    }
+
+    #[test]
+    fn other_versions() {
+        let mut data = encode(&sample(), 1);
+
+        data[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+
+        match open(&Directory::new("newer"), &data) {
+            Err(FormatError::Newer(version)) => assert_eq!(version, FORMAT_VERSION as u64 + 1),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        data[4..8].copy_from_slice(&0u32.to_le_bytes());
+
+        match open(&Directory::new("older"), &data) {
+            Err(FormatError::Corrupt(error)) => assert!(error.contains("unsupported")),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,402 @@
+// Copyright 2015 Jerome Rasky <jerome@rasky.co>
+//
+// Licensed under the Apache License, version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     <http://www.apache.org/licenses/LICENSE-2.0>
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
+// License for the specific language concerning governing permissions and
+// limitations under the License.
+
+// changes to the completions since the index was written, appended after every
+// command and folded into the index when enough have built up
+//
+// everything is little endian, as in the index:
+//
+//   magic "BISJ", u32 format version, u64 generation of the index it follows
+//   per change: u32 length of the rest, u64 time, u8 kind, u32 length, line, then
+//     chosen (0): u32 length, directory
+//     ran (1): i32 status, f64 seconds, u8 1 if a u32 length and directory follow
+//     forgot (2): nothing
+//
+// appends aren't synced, so a crash can lose the last few changes, or leave the
+// last one half written, which is ignored and cut off by the next append
+use std::io::prelude::*;
+
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::os::unix::ffi::OsStrExt;
+
+use std::io;
+use std::str;
+
+use types::Change;
+use index::{self, Reader};
+use store::FormatError;
+
+const MAGIC: &[u8] = b"BISJ";
+
+// as with the index, other versions are refused rather than misread
+pub const FORMAT_VERSION: u32 = 1;
+
+const HEADER_LENGTH: usize = 16;
+
+const CHOSEN: u8 = 0;
+const RAN: u8 = 1;
+const FORGOT: u8 = 2;
+
+// what a journal starts with, if it's one we can read
+enum Header {
+    Valid(u64),
+    Newer(u32),
+    Corrupt(String),
+}
+
+fn header(data: &[u8]) -> Header {
+    let mut reader = Reader::new(data, 0);
+
+    match reader.take(MAGIC.len()) {
+        Ok(magic) if magic == MAGIC => {}
+        Ok(_) => return Header::Corrupt("not a bis2 journal".into()),
+        Err(error) => return Header::Corrupt(error),
+    }
+
+    let version = match reader.u32() {
+        Ok(version) => version,
+        Err(error) => return Header::Corrupt(error),
+    };
+
+    match version {
+        FORMAT_VERSION => match reader.u64() {
+            Ok(generation) => Header::Valid(generation),
+            Err(error) => Header::Corrupt(error),
+        },
+        newer if newer > FORMAT_VERSION => Header::Newer(newer),
+        older => Header::Corrupt(format!("unsupported journal version {}", older)),
+    }
+}
+
+// the changes made since the given generation of the index was written, and when
+pub fn read(path: &Path, generation: u64) -> io::Result<Result<Vec<(u64, Change)>, FormatError>> {
+    let mut data = Vec::new();
+
+    match File::open(path) {
+        Ok(mut file) => file.read_to_end(&mut data)?,
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => return Ok(Ok(Vec::new())),
+        Err(error) => return Err(error),
+    };
+
+    Ok(decode(&data, generation))
+}
+
+fn decode(data: &[u8], generation: u64) -> Result<Vec<(u64, Change)>, FormatError> {
+    match header(data) {
+        Header::Valid(written) if written == generation => {}
+        Header::Valid(written) => {
+            // left over from before the last compaction
+            debug!("Ignoring journal for generation {}, the index is {}", written, generation);
+            return Ok(Vec::new());
+        }
+        Header::Newer(version) => return Err(FormatError::Newer(version as u64)),
+        Header::Corrupt(error) => return Err(FormatError::Corrupt(error)),
+    }
+
+    let (entries, end) = entries(data);
+
+    if end < data.len() {
+        debug!("Ignoring half written change at the end of the journal");
+    }
+
+    entries.into_iter().map(|entry| change(entry).map_err(FormatError::Corrupt)).collect()
+}
+
+// every complete change after the header, and where the last of them ends
+fn entries(data: &[u8]) -> (Vec<&[u8]>, usize) {
+    let mut reader = Reader::new(data, HEADER_LENGTH);
+    let mut entries = Vec::new();
+    let mut end = HEADER_LENGTH;
+
+    while let Ok(length) = reader.u32() {
+        match reader.take(length as usize) {
+            Ok(entry) => {
+                end += 4 + entry.len();
+                entries.push(entry);
+            }
+            Err(_) => break,
+        }
+    }
+
+    (entries, end)
+}
+
+fn change(entry: &[u8]) -> Result<(u64, Change), String> {
+    let mut reader = Reader::new(entry, 0);
+
+    let time = reader.u64()?;
+    let kind = reader.u8()?;
+    let line = str::from_utf8(reader.bytes()?).map_err(|error| error.to_string())?.to_string();
+
+    let change = match kind {
+        CHOSEN => Change::Chosen(line, reader.path()?.to_path_buf()),
+        RAN => {
+            let status = reader.u32()? as i32;
+            let seconds = reader.f64()?;
+
+            let directory = if reader.u8()? == 1 {
+                Some(reader.path()?.to_path_buf())
+            } else {
+                None
+            };
+
+            Change::Ran(line, status, seconds, directory)
+        }
+        FORGOT => Change::Forgot(line),
+        other => return Err(format!("unknown change {}", other)),
+    };
+
+    Ok((time, change))
+}
+
+fn put_path(data: &mut Vec<u8>, path: &Path) {
+    index::put_bytes(data, path.as_os_str().as_bytes());
+}
+
+fn encode(changes: &[Change], now: u64) -> Vec<u8> {
+    let mut data = Vec::new();
+
+    for change in changes {
+        let mut entry = Vec::new();
+
+        entry.extend_from_slice(&now.to_le_bytes());
+
+        match *change {
+            Change::Chosen(ref line, ref path) => {
+                entry.push(CHOSEN);
+                index::put_bytes(&mut entry, line.as_bytes());
+                put_path(&mut entry, path);
+            }
+            Change::Ran(ref line, status, seconds, ref directory) => {
+                entry.push(RAN);
+                index::put_bytes(&mut entry, line.as_bytes());
+                entry.extend_from_slice(&status.to_le_bytes());
+                entry.extend_from_slice(&seconds.to_bits().to_le_bytes());
+
+                match *directory {
+                    Some(ref directory) => {
+                        entry.push(1);
+                        put_path(&mut entry, directory);
+                    }
+                    None => entry.push(0),
+                }
+            }
+            Change::Forgot(ref line) => {
+                entry.push(FORGOT);
+                index::put_bytes(&mut entry, line.as_bytes());
+            }
+        }
+
+        index::put_bytes(&mut data, &entry);
+    }
+
+    data
+}
+
+// add changes for the given generation of the index, starting over if the journal
+// was for another one, and return how big it's got; the store lock must be held
+pub fn append(path: &Path, generation: u64, now: u64, changes: &[Change]) -> io::Result<u64> {
+    let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+
+    let mut existing = Vec::new();
+    (&file).read_to_end(&mut existing)?;
+
+    let mut data = Vec::new();
+
+    match header(&existing) {
+        Header::Valid(written) if written == generation => {
+            let (_, end) = entries(&existing);
+
+            if end < existing.len() {
+                // a crash left half a change, which would swallow the ones after it
+                debug!("Cutting off half written change at the end of the journal");
+                file.set_len(end as u64)?;
+            }
+        }
+        Header::Newer(version) => {
+            let error = FormatError::Newer(version as u64).to_string();
+            return Err(io::Error::new(io::ErrorKind::InvalidData, error));
+        }
+        _ => {
+            // empty, stale, or unreadable, none of which is worth keeping
+            file.set_len(0)?;
+
+            data.extend_from_slice(MAGIC);
+            data.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+            data.extend_from_slice(&generation.to_le_bytes());
+        }
+    }
+
+    let length = if data.is_empty() { file.metadata()?.len() } else { 0 };
+
+    data.extend(encode(changes, now));
+
+    if let Err(error) = file.write_all(&data) {
+        // don't leave half a change for the next one to be appended after
+        let _ = file.set_len(length);
+        return Err(error);
+    }
+
+    Ok(length + data.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use testing::Directory;
+
+    const NOW: u64 = 1_600_000_000;
+
+    fn changes() -> Vec<Change> {
+        vec![
+            Change::Chosen("make".into(), "/src".into()),
+            Change::Ran("false".into(), 1, 0.25, None),
+            Change::Ran("cargo test".into(), -1, 30.0, Some("/src/bis2".into())),
+            Change::Forgot("rm -rf /".into()),
+        ]
+    }
+
+    fn timed(changes: Vec<Change>, time: u64) -> Vec<(u64, Change)> {
+        changes.into_iter().map(|change| (time, change)).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let directory = Directory::new("round-trip");
+        let path = directory.join("completions.journal");
+
+        append(&path, 3, NOW, &changes()[..2]).unwrap();
+        let length = append(&path, 3, NOW + 1, &changes()[2..]).unwrap();
+
+        let mut expected = timed(changes()[..2].to_vec(), NOW);
+        expected.extend(timed(changes()[2..].to_vec(), NOW + 1));
+
+        assert_eq!(length, fs::metadata(&path).unwrap().len());
+        assert_eq!(read(&path, 3).unwrap().unwrap(), expected);
+    }
+
+    #[test]
+    fn missing() {
+        assert_eq!(read(&Directory::new("missing").join("completions.journal"), 3).unwrap().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn other_generation() {
+        let directory = Directory::new("other-generation");
+        let path = directory.join("completions.journal");
+
+        append(&path, 3, NOW, &changes()).unwrap();
+
+        assert_eq!(read(&path, 4).unwrap().unwrap(), vec![]);
+
+        // and appending for the new one starts over
+        append(&path, 4, NOW + 1, &changes()[..1]).unwrap();
+
+        assert_eq!(read(&path, 4).unwrap().unwrap(), timed(changes()[..1].to_vec(), NOW + 1));
+        assert_eq!(read(&path, 3).unwrap().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn half_written() {
+        let directory = Directory::new("half-written");
+        let path = directory.join("completions.journal");
+
+        append(&path, 3, NOW, &changes()).unwrap();
+
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() - 3]).unwrap();
+
+        assert_eq!(read(&path, 3).unwrap().unwrap(), timed(changes()[..3].to_vec(), NOW));
+    }
+
+    #[test]
+    fn append_after_half_written() {
+        let directory = Directory::new("append-after-half-written");
+        let path = directory.join("completions.journal");
+
+        append(&path, 3, NOW, &changes()[..3]).unwrap();
+
+        // as if a crash stopped the next append partway through
+        let mut data = fs::read(&path).unwrap();
+        let complete = data.len() as u64;
+        data.extend_from_slice(&encode(&changes()[3..], NOW)[..5]);
+        fs::write(&path, &data).unwrap();
+
+        let length = append(&path, 3, NOW + 1, &changes()[3..]).unwrap();
+
+        let mut expected = timed(changes()[..3].to_vec(), NOW);
+        expected.extend(timed(changes()[3..].to_vec(), NOW + 1));
+
+        assert_eq!(length, complete + encode(&changes()[3..], NOW + 1).len() as u64);
+        assert_eq!(read(&path, 3).unwrap().unwrap(), expected);
+    }
+
+    #[test]
+    fn garbage() {
+        let directory = Directory::new("garbage");
+        let path = directory.join("completions.journal");
+
+        fs::write(&path, b"garbage").unwrap();
+
+        match read(&path, 3).unwrap() {
+            Err(FormatError::Corrupt(_)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // which appending replaces
+        append(&path, 3, NOW, &changes()).unwrap();
+
+        assert_eq!(read(&path, 3).unwrap().unwrap(), timed(changes(), NOW));
+    }
+
+    #[test]
+    fn unknown_change() {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        data.extend_from_slice(&3u64.to_le_bytes());
+
+        let mut entry = NOW.to_le_bytes().to_vec();
+        entry.push(9);
+        index::put_bytes(&mut entry, b"make");
+        index::put_bytes(&mut data, &entry);
+
+        match decode(&data, 3) {
+            Err(FormatError::Corrupt(error)) => assert!(error.contains("unknown")),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn newer_version() {
+        let directory = Directory::new("newer");
+        let path = directory.join("completions.journal");
+
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        data.extend_from_slice(&3u64.to_le_bytes());
+        fs::write(&path, &data).unwrap();
+
+        match read(&path, 3).unwrap() {
+            Err(FormatError::Newer(version)) => assert_eq!(version, FORMAT_VERSION as u64 + 1),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        assert_eq!(append(&path, 3, NOW, &changes()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::read(&path).unwrap(), data);
+    }
+}
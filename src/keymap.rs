@@ -0,0 +1,489 @@
+// Copyright 2015 Jerome Rasky <jerome@rasky.co>
+//
+// Licensed under the Apache License, version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     <http://www.apache.org/licenses/LICENSE-2.0>
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
+// License for the specific language concerning governing permissions and
+// limitations under the License.
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use keys::{Key, KeyPress, SHIFT, ALT, CTRL};
+use query::Edit;
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    SelectNext,
+    SelectPrev,
+    PageDown,
+    PageUp,
+    Accept,
+    // accept, and have the shell run the line straight away
+    AcceptAndExecute,
+    Cancel,
+    ClearQuery,
+    // hide the selected line and forget where it was used
+    DeleteEntry,
+    // switch between vi insert and normal mode
+    ToggleMode,
+    BackwardChar,
+    ForwardChar,
+    BeginningOfLine,
+    EndOfLine,
+    BackwardWord,
+    ForwardWord,
+    BackwardDeleteChar,
+    DeleteChar,
+    BackwardKillWord,
+    KillLine,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Emacs,
+    Vi,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeymapConfig {
+    pub mode: Mode,
+    // key name to action, over the defaults for the mode
+    pub bindings: HashMap<String, Action>,
+    // vi normal mode bindings
+    pub normal: HashMap<String, Action>,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum Binding {
+    Action(Action),
+    Input(char),
+    // the keymap took care of it
+    Handled,
+    Unbound,
+}
+
+pub struct Keymap {
+    mode: Mode,
+    // in vi normal mode
+    normal_mode: bool,
+    insert: HashMap<KeyPress, Action>,
+    normal: HashMap<KeyPress, Action>,
+}
+
+const EMACS_BINDINGS: &[(&str, Action)] = &[
+    ("enter", Action::Accept),
+    ("alt-enter", Action::AcceptAndExecute),
+    ("esc", Action::Cancel),
+    ("ctrl-c", Action::Cancel),
+    ("ctrl-d", Action::Cancel),
+    ("ctrl-g", Action::Cancel),
+    ("ctrl-r", Action::SelectNext),
+    ("ctrl-s", Action::SelectPrev),
+    ("ctrl-n", Action::SelectNext),
+    ("ctrl-p", Action::SelectPrev),
+    ("down", Action::SelectNext),
+    ("up", Action::SelectPrev),
+    ("pagedown", Action::PageDown),
+    ("pageup", Action::PageUp),
+    ("shift-delete", Action::DeleteEntry),
+    ("ctrl-u", Action::ClearQuery),
+    ("ctrl-b", Action::BackwardChar),
+    ("ctrl-f", Action::ForwardChar),
+    ("left", Action::BackwardChar),
+    ("right", Action::ForwardChar),
+    ("ctrl-a", Action::BeginningOfLine),
+    ("ctrl-e", Action::EndOfLine),
+    ("home", Action::BeginningOfLine),
+    ("end", Action::EndOfLine),
+    ("alt-b", Action::BackwardWord),
+    ("alt-f", Action::ForwardWord),
+    ("ctrl-left", Action::BackwardWord),
+    ("ctrl-right", Action::ForwardWord),
+    ("alt-left", Action::BackwardWord),
+    ("alt-right", Action::ForwardWord),
+    ("backspace", Action::BackwardDeleteChar),
+    ("delete", Action::DeleteChar),
+    ("ctrl-w", Action::BackwardKillWord),
+    ("ctrl-k", Action::KillLine),
+];
+
+// vi insert mode is emacs with escape going to normal mode
+const VI_INSERT_BINDINGS: &[(&str, Action)] = &[
+    ("esc", Action::ToggleMode),
+];
+
+const VI_NORMAL_BINDINGS: &[(&str, Action)] = &[
+    ("enter", Action::Accept),
+    ("alt-enter", Action::AcceptAndExecute),
+    ("esc", Action::Cancel),
+    ("ctrl-c", Action::Cancel),
+    ("ctrl-d", Action::Cancel),
+    ("q", Action::Cancel),
+    ("i", Action::ToggleMode),
+    ("j", Action::SelectNext),
+    ("k", Action::SelectPrev),
+    ("ctrl-n", Action::SelectNext),
+    ("ctrl-p", Action::SelectPrev),
+    ("down", Action::SelectNext),
+    ("up", Action::SelectPrev),
+    ("ctrl-f", Action::PageDown),
+    ("ctrl-b", Action::PageUp),
+    ("pagedown", Action::PageDown),
+    ("pageup", Action::PageUp),
+    ("d", Action::DeleteEntry),
+    ("ctrl-u", Action::ClearQuery),
+    ("h", Action::BackwardChar),
+    ("l", Action::ForwardChar),
+    ("left", Action::BackwardChar),
+    ("right", Action::ForwardChar),
+    ("backspace", Action::BackwardChar),
+    ("0", Action::BeginningOfLine),
+    ("^", Action::BeginningOfLine),
+    ("$", Action::EndOfLine),
+    ("home", Action::BeginningOfLine),
+    ("end", Action::EndOfLine),
+    ("b", Action::BackwardWord),
+    ("w", Action::ForwardWord),
+    ("x", Action::DeleteChar),
+    ("delete", Action::DeleteChar),
+    ("X", Action::BackwardDeleteChar),
+    ("D", Action::KillLine),
+];
+
+impl Action {
+    // the input thread stops after these
+    pub fn quits(self) -> bool {
+        matches!(self, Action::Accept | Action::AcceptAndExecute | Action::Cancel)
+    }
+
+    pub fn edit(self) -> Option<Edit> {
+        match self {
+            Action::ClearQuery => Some(Edit::Clear),
+            Action::BackwardChar => Some(Edit::Left),
+            Action::ForwardChar => Some(Edit::Right),
+            Action::BeginningOfLine => Some(Edit::Home),
+            Action::EndOfLine => Some(Edit::End),
+            Action::BackwardWord => Some(Edit::WordLeft),
+            Action::ForwardWord => Some(Edit::WordRight),
+            Action::BackwardDeleteChar => Some(Edit::Backspace),
+            Action::DeleteChar => Some(Edit::Delete),
+            Action::BackwardKillWord => Some(Edit::DeleteWord),
+            Action::KillLine => Some(Edit::KillLine),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Mode, String> {
+        match name {
+            "emacs" => Ok(Mode::Emacs),
+            "vi" => Ok(Mode::Vi),
+            _ => Err(format!("unknown keymap mode {:?}", name)),
+        }
+    }
+}
+
+impl Default for KeymapConfig {
+    fn default() -> KeymapConfig {
+        KeymapConfig {
+            mode: Mode::Emacs,
+            bindings: HashMap::new(),
+            normal: HashMap::new(),
+        }
+    }
+}
+
+impl Keymap {
+    pub fn create(config: &KeymapConfig) -> Result<Keymap, String> {
+        let mut insert = HashMap::new();
+        let mut normal = HashMap::new();
+
+        bind_all(&mut insert, EMACS_BINDINGS.iter().cloned())?;
+
+        if config.mode == Mode::Vi {
+            bind_all(&mut insert, VI_INSERT_BINDINGS.iter().cloned())?;
+            bind_all(&mut normal, VI_NORMAL_BINDINGS.iter().cloned())?;
+        } else if !config.normal.is_empty() {
+            return Err("normal mode bindings need the vi keymap".into());
+        }
+
+        bind_all(&mut insert, config.bindings.iter().map(|(name, &action)| (name.as_str(), action)))?;
+        bind_all(&mut normal, config.normal.iter().map(|(name, &action)| (name.as_str(), action)))?;
+
+        Ok(Keymap {
+            mode: config.mode,
+            normal_mode: false,
+            insert,
+            normal,
+        })
+    }
+
+    pub fn lookup(&mut self, press: KeyPress) -> Binding {
+        let press = normalize(press);
+
+        let bindings = if self.normal_mode {
+            &self.normal
+        } else {
+            &self.insert
+        };
+
+        match (bindings.get(&press), press) {
+            (Some(&Action::ToggleMode), _) => {
+                if self.mode == Mode::Vi {
+                    self.normal_mode = !self.normal_mode;
+                    Binding::Handled
+                } else {
+                    Binding::Unbound
+                }
+            }
+            (Some(&action), _) => Binding::Action(action),
+            (None, KeyPress { key: Key::Char(chr), modifiers: 0 }) if !self.normal_mode && !chr.is_control() => {
+                Binding::Input(chr)
+            }
+            (None, _) => Binding::Unbound,
+        }
+    }
+}
+
+fn bind_all<'a, I: Iterator<Item = (&'a str, Action)>>(bindings: &mut HashMap<KeyPress, Action>, iter: I) -> Result<(), String> {
+    for (name, action) in iter {
+        bindings.insert(parse_key(name)?, action);
+    }
+
+    Ok(())
+}
+
+// key names look like "ctrl-r", "alt-enter", "shift-tab" or "x"
+pub fn parse_key(name: &str) -> Result<KeyPress, String> {
+    let mut rest = name;
+    let mut modifiers = 0;
+
+    loop {
+        let (prefix, modifier) = if rest.starts_with("ctrl-") {
+            ("ctrl-", CTRL)
+        } else if rest.starts_with("alt-") {
+            ("alt-", ALT)
+        } else if rest.starts_with("shift-") {
+            ("shift-", SHIFT)
+        } else {
+            break;
+        };
+
+        // "alt--" is alt and minus
+        if rest.len() == prefix.len() {
+            break;
+        }
+
+        modifiers |= modifier;
+        rest = &rest[prefix.len()..];
+    }
+
+    let mut chars = rest.chars();
+    let key = match (chars.next(), chars.next()) {
+        (Some(chr), None) => Key::Char(chr),
+        _ => match rest {
+            "enter" | "return" => Key::Enter,
+            "tab" => Key::Tab,
+            "backtab" => Key::BackTab,
+            "backspace" => Key::Backspace,
+            "esc" | "escape" => Key::Escape,
+            "space" => Key::Char(' '),
+            "up" => Key::Up,
+            "down" => Key::Down,
+            "left" => Key::Left,
+            "right" => Key::Right,
+            "home" => Key::Home,
+            "end" => Key::End,
+            "insert" => Key::Insert,
+            "delete" => Key::Delete,
+            "pageup" | "pgup" => Key::PageUp,
+            "pagedown" | "pgdn" => Key::PageDown,
+            _ => match rest.trim_start_matches('f').parse() {
+                Ok(number @ 1..=12) if rest.starts_with('f') => Key::F(number),
+                _ => return Err(format!("unknown key {:?}", name)),
+            },
+        },
+    };
+
+    Ok(normalize(KeyPress::new(key, modifiers)))
+}
+
+// spell keys the way the decoder reports them
+fn normalize(press: KeyPress) -> KeyPress {
+    match (press.key, press.modifiers) {
+        // terminals send these as plain control characters
+        (Key::Char('h'), CTRL) | (Key::Char('?'), CTRL) => KeyPress::plain(Key::Backspace),
+        (Key::Char('i'), CTRL) => KeyPress::plain(Key::Tab),
+        (Key::Char('j'), CTRL) | (Key::Char('m'), CTRL) => KeyPress::plain(Key::Enter),
+        (Key::Char('['), CTRL) => KeyPress::plain(Key::Escape),
+        (Key::Tab, SHIFT) => KeyPress::plain(Key::BackTab),
+        (Key::Char(chr), modifiers) if modifiers & CTRL != 0 => {
+            KeyPress::new(Key::Char(chr.to_ascii_lowercase()), modifiers)
+        }
+        (Key::Char(chr), modifiers) if modifiers & SHIFT != 0 => {
+            let upper = chr.to_uppercase().next().unwrap_or(chr);
+            KeyPress::new(Key::Char(upper), modifiers & !SHIFT)
+        }
+        _ => press,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(key: Key, modifiers: u8) -> KeyPress {
+        KeyPress::new(key, modifiers)
+    }
+
+    fn create(mode: Mode, bindings: &[(&str, Action)], normal: &[(&str, Action)]) -> Keymap {
+        let owned = |list: &[(&str, Action)]| list.iter().map(|&(name, action)| (name.to_string(), action)).collect();
+
+        Keymap::create(&KeymapConfig { mode, bindings: owned(bindings), normal: owned(normal) }).unwrap()
+    }
+
+    fn lookup(keymap: &mut Keymap, name: &str) -> Binding {
+        keymap.lookup(parse_key(name).unwrap())
+    }
+
+    #[test]
+    fn modifiers() {
+        assert_eq!(parse_key("ctrl-left"), Ok(press(Key::Left, CTRL)));
+        assert_eq!(parse_key("alt-enter"), Ok(press(Key::Enter, ALT)));
+        assert_eq!(parse_key("shift-delete"), Ok(press(Key::Delete, SHIFT)));
+        assert_eq!(parse_key("ctrl-alt-shift-up"), Ok(press(Key::Up, CTRL | ALT | SHIFT)));
+        assert_eq!(parse_key("alt-ctrl-x"), Ok(press(Key::Char('x'), CTRL | ALT)));
+        assert_eq!(parse_key("alt--"), Ok(press(Key::Char('-'), ALT)));
+        assert_eq!(parse_key("ctrl-"), Err("unknown key \"ctrl-\"".into()));
+    }
+
+    #[test]
+    fn named_keys() {
+        let table = [
+            ("enter", Key::Enter),
+            ("return", Key::Enter),
+            ("tab", Key::Tab),
+            ("backtab", Key::BackTab),
+            ("backspace", Key::Backspace),
+            ("esc", Key::Escape),
+            ("escape", Key::Escape),
+            ("space", Key::Char(' ')),
+            ("up", Key::Up),
+            ("down", Key::Down),
+            ("left", Key::Left),
+            ("right", Key::Right),
+            ("home", Key::Home),
+            ("end", Key::End),
+            ("insert", Key::Insert),
+            ("delete", Key::Delete),
+            ("pageup", Key::PageUp),
+            ("pgup", Key::PageUp),
+            ("pagedown", Key::PageDown),
+            ("pgdn", Key::PageDown),
+            ("f1", Key::F(1)),
+            ("f12", Key::F(12)),
+            ("x", Key::Char('x')),
+            ("f", Key::Char('f')),
+            ("\u{e9}", Key::Char('\u{e9}')),
+        ];
+
+        for &(name, key) in table.iter() {
+            assert_eq!(parse_key(name), Ok(KeyPress::plain(key)), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn unknown_names() {
+        for name in ["", "f0", "f13", "fx", "enterr", "hyper-x", "ctrl-foo", "Enter"].iter() {
+            assert!(parse_key(name).is_err(), "{:?}", name);
+        }
+
+        let config = KeymapConfig {
+            bindings: vec![("ctrl-nope".to_string(), Action::Accept)].into_iter().collect(),
+            ..KeymapConfig::default()
+        };
+
+        assert!(Keymap::create(&config).is_err());
+    }
+
+    #[test]
+    fn aliases() {
+        assert_eq!(parse_key("ctrl-h"), Ok(KeyPress::plain(Key::Backspace)));
+        assert_eq!(parse_key("ctrl-?"), Ok(KeyPress::plain(Key::Backspace)));
+        assert_eq!(parse_key("ctrl-i"), Ok(KeyPress::plain(Key::Tab)));
+        assert_eq!(parse_key("ctrl-j"), Ok(KeyPress::plain(Key::Enter)));
+        assert_eq!(parse_key("ctrl-m"), Ok(KeyPress::plain(Key::Enter)));
+        assert_eq!(parse_key("ctrl-["), Ok(KeyPress::plain(Key::Escape)));
+        assert_eq!(parse_key("shift-tab"), Ok(KeyPress::plain(Key::BackTab)));
+        assert_eq!(parse_key("shift-a"), Ok(KeyPress::plain(Key::Char('A'))));
+        assert_eq!(parse_key("ctrl-R"), Ok(press(Key::Char('r'), CTRL)));
+        assert_eq!(parse_key("alt-shift-x"), Ok(press(Key::Char('X'), ALT)));
+    }
+
+    #[test]
+    fn defaults() {
+        let mut keymap = create(Mode::Emacs, &[], &[]);
+
+        assert_eq!(lookup(&mut keymap, "ctrl-r"), Binding::Action(Action::SelectNext));
+        assert_eq!(lookup(&mut keymap, "enter"), Binding::Action(Action::Accept));
+        assert_eq!(lookup(&mut keymap, "esc"), Binding::Action(Action::Cancel));
+
+        assert_eq!(lookup(&mut keymap, "x"), Binding::Input('x'));
+        assert_eq!(lookup(&mut keymap, "alt-x"), Binding::Unbound);
+    }
+
+    #[test]
+    fn overrides_win() {
+        let mut keymap = create(Mode::Emacs, &[("ctrl-r", Action::Cancel), ("tab", Action::AcceptAndExecute)], &[]);
+
+        assert_eq!(lookup(&mut keymap, "ctrl-r"), Binding::Action(Action::Cancel));
+        assert_eq!(lookup(&mut keymap, "tab"), Binding::Action(Action::AcceptAndExecute));
+        assert_eq!(lookup(&mut keymap, "ctrl-s"), Binding::Action(Action::SelectPrev));
+
+        // bound under an alias, looked up as the decoder reports it
+        let mut keymap = create(Mode::Emacs, &[("ctrl-h", Action::BackwardKillWord)], &[]);
+        assert_eq!(lookup(&mut keymap, "backspace"), Binding::Action(Action::BackwardKillWord));
+    }
+
+    #[test]
+    fn vi_toggles() {
+        let mut keymap = create(Mode::Vi, &[], &[("x", Action::Cancel)]);
+
+        assert_eq!(lookup(&mut keymap, "j"), Binding::Input('j'));
+        assert_eq!(lookup(&mut keymap, "esc"), Binding::Handled);
+
+        // normal mode, with its own bindings and nothing typed
+        assert_eq!(lookup(&mut keymap, "j"), Binding::Action(Action::SelectNext));
+        assert_eq!(lookup(&mut keymap, "x"), Binding::Action(Action::Cancel));
+        assert_eq!(lookup(&mut keymap, "z"), Binding::Unbound);
+        assert_eq!(lookup(&mut keymap, "i"), Binding::Handled);
+
+        assert_eq!(lookup(&mut keymap, "j"), Binding::Input('j'));
+    }
+
+    #[test]
+    fn toggle_needs_vi() {
+        let mut keymap = create(Mode::Emacs, &[("ctrl-t", Action::ToggleMode)], &[]);
+
+        assert_eq!(lookup(&mut keymap, "ctrl-t"), Binding::Unbound);
+
+        let config = KeymapConfig {
+            normal: vec![("j".to_string(), Action::Cancel)].into_iter().collect(),
+            ..KeymapConfig::default()
+        };
+
+        assert!(Keymap::create(&config).is_err());
+    }
+}
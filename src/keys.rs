@@ -0,0 +1,512 @@
+// Copyright 2015 Jerome Rasky <jerome@rasky.co>
+//
+// Licensed under the Apache License, version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     <http://www.apache.org/licenses/LICENSE-2.0>
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
+// License for the specific language concerning governing permissions and
+// limitations under the License.
+
+// decodes terminal input into key presses, assuming ANSI/xterm sequences
+use std::mem;
+
+use constants::*;
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Hash)]
+pub enum Key {
+    Char(char),
+    Enter,
+    Tab,
+    BackTab,
+    Backspace,
+    Escape,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    Insert,
+    Delete,
+    PageUp,
+    PageDown,
+    F(u8),
+}
+
+// xterm encodes modifiers as one plus these bits
+pub const SHIFT: u8 = 1;
+pub const ALT: u8 = 2;
+pub const CTRL: u8 = 4;
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Hash)]
+pub struct KeyPress {
+    pub key: Key,
+    pub modifiers: u8,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum Decoded {
+    Key(KeyPress),
+    // in the middle of a sequence
+    Pending,
+    // a sequence we don't understand, with what was read of it
+    Unknown(String),
+    // everything between the bracketed paste markers
+    Paste(String),
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+    Ss3,
+    Paste,
+}
+
+pub struct Decoder {
+    state: State,
+    sequence: String,
+}
+
+impl KeyPress {
+    pub fn new(key: Key, modifiers: u8) -> KeyPress {
+        KeyPress { key, modifiers }
+    }
+
+    pub fn plain(key: Key) -> KeyPress {
+        KeyPress::new(key, 0)
+    }
+
+    fn with(self, modifiers: u8) -> KeyPress {
+        KeyPress::new(self.key, self.modifiers | modifiers)
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder {
+        Decoder {
+            state: State::Ground,
+            sequence: String::new(),
+        }
+    }
+}
+
+impl Decoder {
+    pub fn feed(&mut self, chr: char) -> Decoded {
+        match self.state {
+            State::Ground => {
+                if chr == ESC {
+                    self.state = State::Escape;
+                    Decoded::Pending
+                } else {
+                    Decoded::Key(decode_char(chr))
+                }
+            }
+            State::Escape => {
+                match chr {
+                    '[' => {
+                        self.state = State::Csi;
+                        Decoded::Pending
+                    }
+                    'O' => {
+                        self.state = State::Ss3;
+                        Decoded::Pending
+                    }
+                    _ => {
+                        // ESC before a key is how terminals send alt
+                        self.state = State::Ground;
+                        Decoded::Key(decode_char(chr).with(ALT))
+                    }
+                }
+            }
+            State::Csi => {
+                match chr {
+                    // parameter and intermediate bytes
+                    '\u{20}'..='\u{3f}' => {
+                        self.sequence.push(chr);
+                        Decoded::Pending
+                    }
+                    '~' if self.sequence == "200" => {
+                        self.state = State::Paste;
+                        self.sequence.clear();
+                        Decoded::Pending
+                    }
+                    // final byte
+                    '\u{40}'..='\u{7e}' => {
+                        let decoded = decode_csi(&self.sequence, chr);
+                        self.finish(decoded, '[', chr)
+                    }
+                    _ => self.finish(None, '[', chr),
+                }
+            }
+            State::Ss3 => {
+                if chr.is_ascii_digit() || chr == ';' {
+                    // some terminals put a modifier before the final byte
+                    self.sequence.push(chr);
+                    Decoded::Pending
+                } else {
+                    let decoded = decode_ss3(&self.sequence, chr);
+                    self.finish(decoded, 'O', chr)
+                }
+            }
+            State::Paste => {
+                self.sequence.push(chr);
+
+                if self.sequence.ends_with(PASTE_END) {
+                    let length = self.sequence.len() - PASTE_END.len();
+                    self.sequence.truncate(length);
+
+                    if self.sequence.len() > PASTE_LIMIT {
+                        let cut = paste_cut(&self.sequence);
+                        self.sequence.truncate(cut);
+                    }
+
+                    self.state = State::Ground;
+
+                    Decoded::Paste(mem::take(&mut self.sequence))
+                } else {
+                    if self.sequence.len() > PASTE_LIMIT + PASTE_END.len() {
+                        // past the limit, only keep enough to see the end marker
+                        let cut = paste_cut(&self.sequence);
+                        self.sequence.remove(cut);
+                    }
+
+                    Decoded::Pending
+                }
+            }
+        }
+    }
+
+    // waiting for the rest of an escape sequence
+    pub fn is_pending(&self) -> bool {
+        self.state != State::Ground && self.state != State::Paste
+    }
+
+    // nothing followed in time, so whatever we have is all there is
+    pub fn timeout(&mut self) -> Decoded {
+        let decoded = match (self.state, self.sequence.is_empty()) {
+            (State::Ground, _) | (State::Paste, _) => return Decoded::Pending,
+            (State::Escape, _) => Some(KeyPress::plain(Key::Escape)),
+            // alt-[ and alt-O look like the start of a sequence
+            (State::Csi, true) => Some(KeyPress::new(Key::Char('['), ALT)),
+            (State::Ss3, true) => Some(KeyPress::new(Key::Char('O'), ALT)),
+            (State::Csi, false) | (State::Ss3, false) => None,
+        };
+
+        let sequence = match self.state {
+            State::Csi => format!("[{}", self.sequence),
+            State::Ss3 => format!("O{}", self.sequence),
+            _ => String::new(),
+        };
+
+        self.state = State::Ground;
+        self.sequence.clear();
+
+        match decoded {
+            Some(key) => Decoded::Key(key),
+            None => Decoded::Unknown(sequence),
+        }
+    }
+
+    fn finish(&mut self, decoded: Option<KeyPress>, introducer: char, last: char) -> Decoded {
+        let sequence = format!("{}{}{}", introducer, self.sequence, last);
+
+        self.state = State::Ground;
+        self.sequence.clear();
+
+        match decoded {
+            Some(key) => Decoded::Key(key),
+            None => Decoded::Unknown(sequence),
+        }
+    }
+}
+
+// where a paste longer than the limit is cut off
+fn paste_cut(sequence: &str) -> usize {
+    let mut cut = PASTE_LIMIT;
+
+    while !sequence.is_char_boundary(cut) {
+        cut += 1;
+    }
+
+    cut
+}
+
+fn decode_char(chr: char) -> KeyPress {
+    match chr {
+        CR | '\n' => KeyPress::plain(Key::Enter),
+        '\t' => KeyPress::plain(Key::Tab),
+        BSPC | DEL => KeyPress::plain(Key::Backspace),
+        ESC => KeyPress::plain(Key::Escape),
+        '\u{0}' => KeyPress::new(Key::Char(' '), CTRL),
+        // CTRL-A through CTRL-Z
+        '\u{1}'..='\u{1a}' => KeyPress::new(Key::Char((chr as u8 + b'a' - 1) as char), CTRL),
+        // CTRL-\ through CTRL-_
+        '\u{1c}'..='\u{1f}' => KeyPress::new(Key::Char((chr as u8 + b'@') as char), CTRL),
+        _ => KeyPress::plain(Key::Char(chr)),
+    }
+}
+
+// parse "1;5" into numbers, empty parameters are defaults
+fn parameters(sequence: &str) -> Option<Vec<Option<u32>>> {
+    if sequence.is_empty() {
+        return Some(vec![]);
+    }
+
+    sequence.split(';').map(|param| {
+        if param.is_empty() {
+            Some(None)
+        } else {
+            param.parse().ok().map(Some)
+        }
+    }).collect()
+}
+
+fn modifiers(param: Option<&Option<u32>>) -> u8 {
+    match param {
+        Some(&Some(value)) if value > 1 => (value - 1) as u8 & (SHIFT | ALT | CTRL),
+        _ => 0,
+    }
+}
+
+fn decode_csi(sequence: &str, last: char) -> Option<KeyPress> {
+    let params = parameters(sequence)?;
+    let modifiers = modifiers(params.get(1));
+
+    let key = match last {
+        'A' => Key::Up,
+        'B' => Key::Down,
+        'C' => Key::Right,
+        'D' => Key::Left,
+        'H' => Key::Home,
+        'F' => Key::End,
+        'Z' => Key::BackTab,
+        'P' => Key::F(1),
+        'Q' => Key::F(2),
+        'R' => Key::F(3),
+        'S' => Key::F(4),
+        '~' => match params.first() {
+            Some(&Some(1)) | Some(&Some(7)) => Key::Home,
+            Some(&Some(2)) => Key::Insert,
+            Some(&Some(3)) => Key::Delete,
+            Some(&Some(4)) | Some(&Some(8)) => Key::End,
+            Some(&Some(5)) => Key::PageUp,
+            Some(&Some(6)) => Key::PageDown,
+            Some(&Some(code @ 11..=15)) => Key::F((code - 10) as u8),
+            Some(&Some(code @ 17..=21)) => Key::F((code - 11) as u8),
+            Some(&Some(code @ 23..=24)) => Key::F((code - 12) as u8),
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    Some(KeyPress::new(key, modifiers))
+}
+
+fn decode_ss3(sequence: &str, last: char) -> Option<KeyPress> {
+    let params = parameters(sequence)?;
+
+    // either "O5C" or "O1;5C"
+    let modifiers = modifiers(params.last());
+
+    let key = match last {
+        'A' => Key::Up,
+        'B' => Key::Down,
+        'C' => Key::Right,
+        'D' => Key::Left,
+        'H' => Key::Home,
+        'F' => Key::End,
+        'M' => Key::Enter,
+        'P' => Key::F(1),
+        'Q' => Key::F(2),
+        'R' => Key::F(3),
+        'S' => Key::F(4),
+        _ => return None,
+    };
+
+    Some(KeyPress::new(key, modifiers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(input: &str) -> Vec<Decoded> {
+        let mut decoder = Decoder::default();
+
+        input.chars().map(|chr| decoder.feed(chr))
+            .filter(|decoded| *decoded != Decoded::Pending)
+            .collect()
+    }
+
+    fn key(key: Key, modifiers: u8) -> Vec<Decoded> {
+        vec![Decoded::Key(KeyPress::new(key, modifiers))]
+    }
+
+    #[test]
+    fn plain_characters() {
+        assert_eq!(decode("a"), key(Key::Char('a'), 0));
+        assert_eq!(decode("\r"), key(Key::Enter, 0));
+        assert_eq!(decode("\t"), key(Key::Tab, 0));
+        assert_eq!(decode("\u{7f}"), key(Key::Backspace, 0));
+        assert_eq!(decode("\u{12}"), key(Key::Char('r'), CTRL));
+        assert_eq!(decode("\u{0}"), key(Key::Char(' '), CTRL));
+    }
+
+    #[test]
+    fn csi_sequences() {
+        let table = [
+            ("\x1b[A", Key::Up, 0),
+            ("\x1b[D", Key::Left, 0),
+            ("\x1b[1;5C", Key::Right, CTRL),
+            ("\x1b[1;3D", Key::Left, ALT),
+            ("\x1b[1;2A", Key::Up, SHIFT),
+            ("\x1b[H", Key::Home, 0),
+            ("\x1b[F", Key::End, 0),
+            ("\x1b[Z", Key::BackTab, 0),
+            ("\x1b[2~", Key::Insert, 0),
+            ("\x1b[3~", Key::Delete, 0),
+            ("\x1b[3;2~", Key::Delete, SHIFT),
+            ("\x1b[5~", Key::PageUp, 0),
+            ("\x1b[6~", Key::PageDown, 0),
+            ("\x1b[1~", Key::Home, 0),
+            ("\x1b[4~", Key::End, 0),
+            ("\x1b[15~", Key::F(5), 0),
+            ("\x1b[17~", Key::F(6), 0),
+            ("\x1b[24~", Key::F(12), 0),
+        ];
+
+        for &(input, expected, modifiers) in table.iter() {
+            assert_eq!(decode(input), key(expected, modifiers), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn ss3_sequences() {
+        let table = [
+            ("\x1bOA", Key::Up, 0),
+            ("\x1bOH", Key::Home, 0),
+            ("\x1bOM", Key::Enter, 0),
+            ("\x1bOP", Key::F(1), 0),
+            ("\x1bO5C", Key::Right, CTRL),
+            ("\x1bO1;5D", Key::Left, CTRL),
+        ];
+
+        for &(input, expected, modifiers) in table.iter() {
+            assert_eq!(decode(input), key(expected, modifiers), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn alt_keys() {
+        assert_eq!(decode("\x1bx"), key(Key::Char('x'), ALT));
+        assert_eq!(decode("\x1b\r"), key(Key::Enter, ALT));
+        assert_eq!(decode("\x1b\u{7f}"), key(Key::Backspace, ALT));
+    }
+
+    #[test]
+    fn unknown_sequences() {
+        assert_eq!(decode("\x1b[99~"), vec![Decoded::Unknown("[99~".into())]);
+        assert_eq!(decode("\x1b[1;5X"), vec![Decoded::Unknown("[1;5X".into())]);
+        assert_eq!(decode("\x1bOz"), vec![Decoded::Unknown("Oz".into())]);
+    }
+
+    #[test]
+    fn keeps_going_after_a_sequence() {
+        let mut expected = key(Key::Up, 0);
+        expected.extend(key(Key::Char('a'), 0));
+        expected.extend(decode("\x1b[99~"));
+        expected.extend(key(Key::Char('b'), 0));
+
+        assert_eq!(decode("\x1b[Aa\x1b[99~b"), expected);
+    }
+
+    #[test]
+    fn bracketed_paste() {
+        assert_eq!(decode("\x1b[200~git log\x1b[201~"), vec![Decoded::Paste("git log".into())]);
+        assert_eq!(decode("\x1b[200~\x1b[201~"), vec![Decoded::Paste(String::new())]);
+    }
+
+    #[test]
+    fn paste_keeps_escapes_and_newlines() {
+        let pasted = "a\x1b[Ab\nc\x1b[201";
+
+        assert_eq!(decode(&format!("\x1b[200~{}\x1b[201~x", pasted)),
+                   vec![Decoded::Paste(pasted.into()), Decoded::Key(KeyPress::plain(Key::Char('x')))]);
+    }
+
+    #[test]
+    fn paste_ignores_timeout() {
+        let mut decoder = Decoder::default();
+
+        for chr in "\x1b[200~abc".chars() {
+            decoder.feed(chr);
+        }
+
+        assert!(!decoder.is_pending());
+        assert_eq!(decoder.timeout(), Decoded::Pending);
+
+        let rest: Vec<Decoded> = "\x1b[201~".chars().map(|chr| decoder.feed(chr)).collect();
+        assert_eq!(rest.last(), Some(&Decoded::Paste("abc".into())));
+    }
+
+    #[test]
+    fn paste_is_capped() {
+        let mut decoder = Decoder::default();
+        let pasted = "é".repeat(PASTE_LIMIT);
+
+        for chr in "\x1b[200~".chars().chain(pasted.chars()) {
+            assert_eq!(decoder.feed(chr), Decoded::Pending);
+        }
+
+        // the buffer stops growing, but the end marker still gets through
+        assert!(decoder.sequence.len() <= PASTE_LIMIT + PASTE_END.len() + 1);
+
+        let rest: Vec<Decoded> = "\x1b[201~x".chars().map(|chr| decoder.feed(chr)).collect();
+        assert_eq!(rest[rest.len() - 2], Decoded::Paste("é".repeat(PASTE_LIMIT / 2)));
+        assert_eq!(rest.last(), Some(&Decoded::Key(KeyPress::plain(Key::Char('x')))));
+    }
+
+    #[test]
+    fn timeout_after_lone_escape() {
+        let mut decoder = Decoder::default();
+
+        assert_eq!(decoder.timeout(), Decoded::Pending);
+        assert_eq!(decoder.feed('\x1b'), Decoded::Pending);
+        assert!(decoder.is_pending());
+        assert_eq!(decoder.timeout(), Decoded::Key(KeyPress::plain(Key::Escape)));
+        assert!(!decoder.is_pending());
+        assert_eq!(decoder.feed('a'), Decoded::Key(KeyPress::plain(Key::Char('a'))));
+    }
+
+    #[test]
+    fn timeout_after_introducer() {
+        let mut decoder = Decoder::default();
+
+        decoder.feed('\x1b');
+        decoder.feed('[');
+        assert_eq!(decoder.timeout(), Decoded::Key(KeyPress::new(Key::Char('['), ALT)));
+
+        decoder.feed('\x1b');
+        decoder.feed('O');
+        assert_eq!(decoder.timeout(), Decoded::Key(KeyPress::new(Key::Char('O'), ALT)));
+    }
+
+    #[test]
+    fn timeout_partway_through() {
+        let mut decoder = Decoder::default();
+
+        for chr in "\x1b[1;5".chars() {
+            decoder.feed(chr);
+        }
+
+        assert_eq!(decoder.timeout(), Decoded::Unknown("[1;5".into()));
+        assert_eq!(decoder.feed('C'), Decoded::Key(KeyPress::plain(Key::Char('C'))));
+    }
+}
@@ -19,35 +19,99 @@ extern crate log;
 extern crate env_logger;
 extern crate threadpool;
 extern crate flx;
+extern crate unicode_normalization;
 extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 extern crate dirs;
 
 use std::mem;
+use std::process;
+use std::env;
 
 use event_loop::EventLoop;
+use config::Config;
+use cli::{Command, Options};
 
 mod constants;
+mod config;
+mod cli;
+mod commands;
 mod types;
+mod store;
+mod index;
+mod journal;
+mod query;
+mod history;
+mod scoring;
+mod matching;
+mod keys;
+mod keymap;
 mod bis_c;
 mod terminal;
+mod theme;
+mod tparm;
 mod ui;
 mod threads;
 mod event_loop;
+#[cfg(test)]
+mod testing;
 
 fn main() {
     // init logging
     env_logger::init();
 
+    let mut options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("bis2: {}\n\n{}", error, cli::USAGE);
+            process::exit(2);
+        }
+    };
+
+    // these don't need the config, so a broken one can't stop them
+    match options.command {
+        Command::Help => {
+            print!("{}", cli::USAGE);
+            return;
+        }
+        Command::Version => {
+            println!("bis2 {}", env!("CARGO_PKG_VERSION"));
+            return;
+        }
+        Command::Init(ref shell) => process::exit(commands::init(shell)),
+        _ => {}
+    }
+
+    // load configuration before touching the terminal
+    let (mut config, keymap) = match Config::load() {
+        Ok(loaded) => loaded,
+        Err(error) => {
+            eprintln!("bis2: {}", error);
+            process::exit(1);
+        }
+    };
+
+    options.apply(&mut config);
+
+    options.default_query(env::var("BIS2_QUERY").ok());
+
+    match options.command {
+        Command::Stats => process::exit(commands::stats(&config)),
+        Command::Record(ref line) => process::exit(commands::record(&config, &options, line)),
+        Command::Migrate => process::exit(commands::migrate(&config)),
+        _ => {}
+    }
+
     // create the event loop
-    let mut ev_loop = EventLoop::create();
+    let mut ev_loop = EventLoop::create(config, keymap, &options);
 
     // run the event loop
-    ev_loop.run();
+    let status = ev_loop.run();
 
-    // destroy the event loop
+    // destroy the event loop, restoring the terminal
     mem::drop(ev_loop);
 
     // done
+    process::exit(status);
 }
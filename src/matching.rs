@@ -0,0 +1,244 @@
+// Copyright 2015 Jerome Rasky <jerome@rasky.co>
+//
+// Licensed under the Apache License, version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     <http://www.apache.org/licenses/LICENSE-2.0>
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
+// License for the specific language concerning governing permissions and
+// limitations under the License.
+
+use unicode_normalization::UnicodeNormalization;
+
+// flx's own weights, which decide where it thinks a query matched
+const WHITESPACE_FACTOR: f32 = 5.0;
+const WHITESPACE_REDUCE: f32 = 0.5;
+const CLASS_FACTOR: f32 = 3.0;
+const FIRST_FACTOR: f32 = 3.0;
+const CLASS_REDUCE: f32 = 0.5;
+const DIST_WEIGHT: f32 = -10.0;
+const HEAT_WEIGHT: f32 = 5.0;
+const MAX_LEN: usize = 80;
+
+#[derive(PartialEq, Clone, Copy)]
+enum CharClass {
+    Whitespace,
+    Numeric,
+    Alphabetic,
+    First,
+    Other,
+}
+
+// flx doesn't say where a query matched, so score every placement the same way
+// it does and keep the best one, which is the placement the line was ranked by
+pub fn match_positions(line: &str, query: &str) -> Vec<usize> {
+    // flx compares normalized characters and ignores whitespace in the query
+    let query: Vec<char> = query.nfkc().filter(|chr| !chr.is_whitespace()).collect();
+
+    if query.is_empty() {
+        return vec![];
+    }
+
+    let chars: Vec<char> = line.nfkc().take(MAX_LEN + 1).collect();
+    let heat = heat_map(&chars);
+
+    let candidates: Vec<Vec<usize>> = query.iter()
+        .map(|&wanted| (0..chars.len()).filter(|&idx| matches(chars[idx], wanted)).collect())
+        .collect();
+
+    if candidates.iter().any(|list| list.is_empty()) {
+        return vec![];
+    }
+
+    let mut position = vec![0; query.len()];
+    let mut best = None;
+    best_positions(&heat, &candidates, &mut position, 0, &mut best);
+
+    let best = match best {
+        Some((_, best)) => best,
+        None => return vec![],
+    };
+
+    // a composed or expanded character moves everything after it, so map the
+    // normalized positions back onto the characters of the line itself
+    let origins = origins(line, chars.len());
+    let mut positions: Vec<usize> = best.into_iter().map(|idx| origins[idx]).collect();
+    positions.dedup();
+    positions
+}
+
+// how much a match at each character is worth, exactly as flx works it out
+fn heat_map(chars: &[char]) -> Vec<f32> {
+    let mut heat = Vec::with_capacity(chars.len());
+    let mut ws_score: f32 = 0.0;
+    let mut cs_score: f32 = 0.0;
+    let mut class = CharClass::First;
+    let mut changed = false;
+
+    for &chr in chars {
+        if !chr.is_whitespace() && class == CharClass::First {
+            cs_score += FIRST_FACTOR;
+        }
+
+        let next = if chr.is_whitespace() {
+            CharClass::Whitespace
+        } else if chr.is_numeric() {
+            CharClass::Numeric
+        } else if chr.is_alphabetic() {
+            CharClass::Alphabetic
+        } else {
+            CharClass::Other
+        };
+
+        if next == CharClass::Whitespace {
+            class = next;
+            ws_score = WHITESPACE_FACTOR;
+        } else if class != next {
+            class = next;
+
+            if !changed {
+                cs_score += CLASS_FACTOR;
+                changed = true;
+            }
+        } else {
+            changed = false;
+        }
+
+        heat.push(ws_score + cs_score);
+
+        ws_score *= WHITESPACE_REDUCE;
+        if !changed {
+            cs_score *= CLASS_REDUCE;
+        }
+    }
+
+    heat
+}
+
+fn score(heat: &[f32], position: &[usize]) -> f32 {
+    let distance = if position.len() < 2 {
+        0.0
+    } else {
+        position.windows(2)
+            .map(|pair| pair[1] as f32 - pair[0] as f32)
+            .sum::<f32>() / position.len() as f32
+    };
+
+    let heat_sum: f32 = position.iter().map(|&idx| heat[idx]).sum();
+
+    distance * DIST_WEIGHT + heat_sum * HEAT_WEIGHT
+}
+
+// tries placements in the same order as flx, so ties go the same way
+fn best_positions(heat: &[f32], candidates: &[Vec<usize>], position: &mut [usize], idx: usize,
+                  best: &mut Option<(f32, Vec<usize>)>) {
+    if idx == candidates.len() {
+        let score = score(heat, position);
+
+        if best.as_ref().is_none_or(|&(best, _)| score > best) {
+            *best = Some((score, position.to_vec()));
+        }
+
+        return;
+    }
+
+    for &candidate in &candidates[idx] {
+        if idx > 0 && candidate <= position[idx - 1] {
+            continue;
+        }
+
+        position[idx] = candidate;
+        best_positions(heat, candidates, position, idx + 1, best);
+    }
+}
+
+// the character of the line each of the first count normalized characters came from
+fn origins(line: &str, count: usize) -> Vec<usize> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut origins = Vec::with_capacity(count);
+
+    for end in 1..=chars.len() {
+        let normalized = chars[..end].iter().cloned().nfkc().count();
+
+        while origins.len() < normalized.min(count) {
+            origins.push(end - 1);
+        }
+
+        if origins.len() == count {
+            break;
+        }
+    }
+
+    origins
+}
+
+// the same rules as flx, lowercase queries match uppercase characters
+fn matches(chr: char, wanted: char) -> bool {
+    if chr.is_whitespace() {
+        return false;
+    }
+
+    chr == wanted || (chr.is_uppercase() && chr.to_lowercase().any(|lower| lower == wanted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn smart_case() {
+        assert_eq!(match_positions("Git Status", "gs"), vec![0, 4]);
+        assert_eq!(match_positions("Git Status", "GS"), vec![0, 4]);
+        assert_eq!(match_positions("git status", "GS"), Vec::<usize>::new());
+        assert_eq!(match_positions("git Status", "gS"), vec![0, 4]);
+    }
+
+    #[test]
+    fn whitespace() {
+        // spaces in the query are skipped, spaces in the line never match
+        assert_eq!(match_positions("git status", "git st"), vec![0, 1, 2, 4, 5]);
+        assert_eq!(match_positions("git status", " "), Vec::<usize>::new());
+        assert_eq!(match_positions("a b", "a b"), vec![0, 2]);
+    }
+
+    #[test]
+    fn multi_byte() {
+        // positions count characters, not bytes
+        assert_eq!(match_positions("échö hëllo", "ëo"), vec![6, 9]);
+        assert_eq!(match_positions("日本語 ls", "語l"), vec![2, 4]);
+        assert_eq!(match_positions("ÉCOLE", "é"), vec![0]);
+    }
+
+    #[test]
+    fn tightest_match() {
+        // with nothing else to tell them apart, closer characters score higher
+        assert_eq!(match_positions("a_a_ab", "ab"), vec![4, 5]);
+        assert_eq!(match_positions("cargo build cargo test", "ct"), vec![12, 18]);
+        assert_eq!(match_positions("xaxbxab", "ab"), vec![1, 3]);
+    }
+
+    #[test]
+    fn ranked_match() {
+        // flx prefers the starts of words to the tightest match, and so do we
+        assert_eq!(match_positions("xgxs git status", "gs"), vec![5, 9]);
+        assert_eq!(match_positions("ngxs cargo status", "gs"), vec![1, 3]);
+    }
+
+    #[test]
+    fn normalized() {
+        // matching happens on normalized characters, highlighting on the line's own
+        assert_eq!(match_positions("e\u{301}cho done", "éd"), vec![0, 6]);
+        assert_eq!(match_positions("\u{fb01}le", "fil"), vec![0, 1]);
+    }
+
+    #[test]
+    fn no_match() {
+        assert_eq!(match_positions("git status", "gz"), Vec::<usize>::new());
+        assert_eq!(match_positions("git status", ""), Vec::<usize>::new());
+        assert_eq!(match_positions("", "g"), Vec::<usize>::new());
+    }
+}
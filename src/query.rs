@@ -0,0 +1,263 @@
+// Copyright 2015 Jerome Rasky <jerome@rasky.co>
+//
+// Licensed under the Apache License, version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     <http://www.apache.org/licenses/LICENSE-2.0>
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
+// License for the specific language concerning governing permissions and
+// limitations under the License.
+use unicode_width::UnicodeWidthChar;
+
+use std::fmt;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Edit {
+    Backspace,
+    Delete,
+    Clear,
+    Left,
+    Right,
+    Home,
+    End,
+    WordLeft,
+    WordRight,
+    DeleteWord,
+    KillLine,
+}
+
+// the query line being edited, with a cursor between characters
+pub struct Query {
+    chars: Vec<char>,
+    cursor: usize,
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for chr in self.chars.iter() {
+            write!(f, "{}", chr)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Query {
+    pub fn new(text: &str) -> Query {
+        let chars: Vec<char> = text.chars().collect();
+        let cursor = chars.len();
+
+        Query { chars, cursor }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chars.is_empty()
+    }
+
+    // display width of everything before the cursor
+    pub fn cursor_width(&self) -> usize {
+        display_width(self.chars[..self.cursor].iter().cloned())
+    }
+
+    // returns false if the edit did nothing
+    pub fn apply(&mut self, edit: Edit) -> bool {
+        match edit {
+            Edit::Backspace => self.backspace(),
+            Edit::Delete => self.delete(),
+            Edit::Clear => self.clear(),
+            Edit::Left => self.left(),
+            Edit::Right => self.right(),
+            Edit::Home => self.home(),
+            Edit::End => self.end(),
+            Edit::WordLeft => self.word_left(),
+            Edit::WordRight => self.word_right(),
+            Edit::DeleteWord => self.delete_word(),
+            Edit::KillLine => self.kill_line(),
+        }
+    }
+
+    pub fn insert(&mut self, chr: char) -> bool {
+        self.chars.insert(self.cursor, chr);
+        self.cursor += 1;
+        true
+    }
+
+    pub fn insert_str(&mut self, text: &str) -> bool {
+        let chars: Vec<char> = text.chars().collect();
+        let count = chars.len();
+
+        self.chars.splice(self.cursor..self.cursor, chars);
+        self.cursor += count;
+        count > 0
+    }
+
+    pub fn backspace(&mut self) -> bool {
+        if self.cursor == 0 {
+            return false;
+        }
+
+        self.cursor -= 1;
+        self.chars.remove(self.cursor);
+        true
+    }
+
+    pub fn delete(&mut self) -> bool {
+        if self.cursor == self.chars.len() {
+            return false;
+        }
+
+        self.chars.remove(self.cursor);
+        true
+    }
+
+    pub fn clear(&mut self) -> bool {
+        if self.chars.is_empty() {
+            return false;
+        }
+
+        self.chars.clear();
+        self.cursor = 0;
+        true
+    }
+
+    pub fn left(&mut self) -> bool {
+        self.move_to(self.cursor.saturating_sub(1))
+    }
+
+    pub fn right(&mut self) -> bool {
+        self.move_to(self.cursor + 1)
+    }
+
+    pub fn home(&mut self) -> bool {
+        self.move_to(0)
+    }
+
+    pub fn end(&mut self) -> bool {
+        self.move_to(self.chars.len())
+    }
+
+    pub fn word_left(&mut self) -> bool {
+        let target = self.word_start(|chr| chr.is_alphanumeric());
+        self.move_to(target)
+    }
+
+    pub fn word_right(&mut self) -> bool {
+        let mut target = self.cursor;
+
+        while target < self.chars.len() && !self.chars[target].is_alphanumeric() {
+            target += 1;
+        }
+
+        while target < self.chars.len() && self.chars[target].is_alphanumeric() {
+            target += 1;
+        }
+
+        self.move_to(target)
+    }
+
+    // like readline's unix-word-rubout, words are separated by whitespace
+    pub fn delete_word(&mut self) -> bool {
+        let start = self.word_start(|chr| !chr.is_whitespace());
+
+        if start == self.cursor {
+            return false;
+        }
+
+        self.chars.drain(start..self.cursor);
+        self.cursor = start;
+        true
+    }
+
+    pub fn kill_line(&mut self) -> bool {
+        if self.cursor == self.chars.len() {
+            return false;
+        }
+
+        self.chars.truncate(self.cursor);
+        true
+    }
+
+    fn move_to(&mut self, target: usize) -> bool {
+        let target = target.min(self.chars.len());
+
+        if target == self.cursor {
+            return false;
+        }
+
+        self.cursor = target;
+        true
+    }
+
+    // start of the word before the cursor, skipping separators first
+    fn word_start<F: Fn(char) -> bool>(&self, is_word: F) -> usize {
+        let mut start = self.cursor;
+
+        while start > 0 && !is_word(self.chars[start - 1]) {
+            start -= 1;
+        }
+
+        while start > 0 && is_word(self.chars[start - 1]) {
+            start -= 1;
+        }
+
+        start
+    }
+}
+
+// a character at a time, the way terminals advance the cursor
+pub fn display_width<I: IntoIterator<Item = char>>(chars: I) -> usize {
+    chars.into_iter().map(|chr| chr.width().unwrap_or(0)).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // strings where measuring the whole string and measuring each character disagree
+    const TRICKY: &[&str] = &[
+        "\u{644}\u{627}",
+        "\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}\u{200d}\u{1f466}",
+        "\u{2764}\u{fe0f}",
+    ];
+
+    #[test]
+    fn cursor_at_end_is_full_width() {
+        for text in TRICKY {
+            let query = Query::new(text);
+
+            assert_eq!(query.cursor_width(), display_width(text.chars()), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn cursor_width_moves_by_characters() {
+        let mut query = Query::new("a\u{4e2d}b");
+
+        assert_eq!(query.cursor_width(), 4);
+        assert!(query.apply(Edit::Left));
+        assert_eq!(query.cursor_width(), 3);
+        assert!(query.apply(Edit::Left));
+        assert_eq!(query.cursor_width(), 1);
+        assert!(query.apply(Edit::Home));
+        assert_eq!(query.cursor_width(), 0);
+        assert!(!query.apply(Edit::Left));
+    }
+
+    #[test]
+    fn editing() {
+        let mut query = Query::new("git commit");
+
+        assert!(query.apply(Edit::DeleteWord));
+        assert_eq!(query.to_string(), "git ");
+        assert!(query.insert_str("push"));
+        assert!(query.apply(Edit::WordLeft));
+        assert!(query.apply(Edit::KillLine));
+        assert_eq!(query.to_string(), "git ");
+        assert!(query.apply(Edit::Clear));
+        assert!(query.is_empty());
+    }
+}
@@ -0,0 +1,292 @@
+// Copyright 2015 Jerome Rasky <jerome@rasky.co>
+//
+// Licensed under the Apache License, version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     <http://www.apache.org/licenses/LICENSE-2.0>
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
+// License for the specific language concerning governing permissions and
+// limitations under the License.
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use constants::*;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Weights {
+    // (maximum age in seconds, weight), youngest first
+    pub time_buckets: Vec<(u64, f32)>,
+    // (maximum commands ago, weight), for history without timestamps
+    pub position_buckets: Vec<(usize, f32)>,
+    // weight of a visit older than every bucket
+    pub stale: f32,
+    // how strongly repeated visits add up, 1.0 is a plain sum
+    pub frequency: f32,
+    // multiplier on the directory score from Completions
+    pub directory: f32,
+    // how much of its score a command that always fails loses
+    pub failure: f32,
+}
+
+#[derive(Default, Debug)]
+struct Visits {
+    count: usize,
+    weight: f32,
+    directory: f32,
+    failure_rate: f32,
+}
+
+pub struct Frecency {
+    weights: Weights,
+    now: u64,
+    lines: HashMap<String, Visits>,
+}
+
+impl Default for Weights {
+    fn default() -> Weights {
+        Weights {
+            time_buckets: FRECENCY_TIME_BUCKETS.to_vec(),
+            position_buckets: FRECENCY_POSITION_BUCKETS.to_vec(),
+            stale: FRECENCY_STALE_WEIGHT,
+            frequency: FRECENCY_FREQUENCY_EXPONENT,
+            directory: FRECENCY_DIRECTORY_WEIGHT,
+            failure: FRECENCY_FAILURE_WEIGHT,
+        }
+    }
+}
+
+impl Weights {
+    pub fn validate(&self) -> Result<(), String> {
+        let time_sorted = self.time_buckets.windows(2).all(|pair| pair[0].0 < pair[1].0);
+        let position_sorted = self.position_buckets.windows(2).all(|pair| pair[0].0 < pair[1].0);
+
+        if !time_sorted || !position_sorted {
+            return Err("scoring buckets must be in increasing order".into());
+        }
+
+        let weights = self.time_buckets.iter().map(|&(_, weight)| weight)
+            .chain(self.position_buckets.iter().map(|&(_, weight)| weight))
+            .chain(vec![self.stale, self.frequency, self.directory, self.failure]);
+
+        for weight in weights {
+            if !weight.is_finite() || weight < 0.0 {
+                return Err(format!("scoring weight {} must be a non-negative number", weight));
+            }
+        }
+
+        if self.failure > 1.0 {
+            return Err("the failure weight can't be more than 1".into());
+        }
+
+        Ok(())
+    }
+
+    pub fn visit_weight(&self, age: Option<u64>, commands_ago: usize) -> f32 {
+        let bucket = match age {
+            Some(age) => self.time_buckets.iter()
+                .find(|&&(max_age, _)| age <= max_age)
+                .map(|&(_, weight)| weight),
+            None => self.position_buckets.iter()
+                .find(|&&(max_ago, _)| commands_ago <= max_ago)
+                .map(|&(_, weight)| weight),
+        };
+
+        bucket.unwrap_or(self.stale)
+    }
+}
+
+// seconds since the epoch
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+impl Frecency {
+    pub fn new(weights: Weights) -> Frecency {
+        Frecency::at(weights, now())
+    }
+
+    // score as of the given time
+    pub fn at(weights: Weights, now: u64) -> Frecency {
+        Frecency {
+            weights,
+            now,
+            lines: HashMap::new(),
+        }
+    }
+
+    pub fn visit(&mut self, line: &str, timestamp: Option<u64>, commands_ago: usize) {
+        // saturate so clock skew never produces a negative age
+        let age = timestamp.map(|timestamp| self.now.saturating_sub(timestamp));
+        let weight = self.weights.visit_weight(age, commands_ago);
+
+        let visits = self.lines.entry(line.into()).or_default();
+        visits.count += 1;
+        visits.weight += weight;
+    }
+
+    pub fn set_directory(&mut self, line: &str, score: f32) {
+        self.lines.entry(line.into()).or_default().directory = score;
+    }
+
+    pub fn set_failure_rate(&mut self, line: &str, rate: f32) {
+        self.lines.entry(line.into()).or_default().failure_rate = rate;
+    }
+
+    pub fn score(&self, line: &str) -> f32 {
+        match self.lines.get(line) {
+            None => 0.0,
+            Some(visits) => {
+                let count = visits.count as f32;
+
+                // mean visit weight, scaled sub-linearly by how often it ran
+                let frecency = if visits.count > 0 {
+                    visits.weight / count * count.powf(self.weights.frequency)
+                } else {
+                    0.0
+                };
+
+                let demotion = 1.0 - visits.failure_rate * self.weights.failure;
+
+                (frecency + visits.directory * self.weights.directory) * demotion
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_600_000_000;
+    const DAY: u64 = 60 * 60 * 24;
+
+    fn frecency() -> Frecency {
+        Frecency::at(Weights::default(), NOW)
+    }
+
+    #[test]
+    fn recent_beats_frequent_but_old() {
+        let mut frecency = frecency();
+
+        for ago in 0..500 {
+            frecency.visit("old", Some(NOW - 400 * DAY - ago), 0);
+        }
+
+        frecency.visit("new", Some(NOW - 60), 0);
+        frecency.visit("new", Some(NOW - 120), 0);
+
+        assert!(frecency.score("new") > frecency.score("old"));
+    }
+
+    #[test]
+    fn frequency_adds_up() {
+        let mut frecency = frecency();
+
+        frecency.visit("once", Some(NOW - DAY * 2), 0);
+        frecency.visit("twice", Some(NOW - DAY * 2), 0);
+        frecency.visit("twice", Some(NOW - DAY * 2), 0);
+
+        assert!(frecency.score("twice") > frecency.score("once"));
+        assert_eq!(frecency.score("unknown"), 0.0);
+    }
+
+    #[test]
+    fn timestamped_buckets() {
+        let weights = Weights::default();
+
+        assert_eq!(weights.visit_weight(Some(0), 10_000), 100.0);
+        assert_eq!(weights.visit_weight(Some(DAY), 0), 100.0);
+        assert_eq!(weights.visit_weight(Some(DAY * 2), 0), 60.0);
+        assert_eq!(weights.visit_weight(Some(DAY * 60), 0), 10.0);
+        assert_eq!(weights.visit_weight(Some(DAY * 1000), 0), 0.5);
+    }
+
+    #[test]
+    fn position_buckets() {
+        let weights = Weights::default();
+
+        assert_eq!(weights.visit_weight(None, 0), 100.0);
+        assert_eq!(weights.visit_weight(None, 101), 60.0);
+        assert_eq!(weights.visit_weight(None, 1000), 30.0);
+        assert_eq!(weights.visit_weight(None, 30_000), 0.5);
+    }
+
+    #[test]
+    fn timestamps_win_over_position() {
+        let mut frecency = frecency();
+
+        // the last command in the file, but from long ago
+        frecency.visit("stale", Some(NOW - DAY * 1000), 0);
+        frecency.visit("untimed", None, 0);
+
+        assert_eq!(frecency.score("stale"), 0.5);
+        assert_eq!(frecency.score("untimed"), 100.0);
+    }
+
+    #[test]
+    fn clock_skew() {
+        let mut frecency = frecency();
+        frecency.visit("future", Some(NOW + DAY), 0);
+
+        assert_eq!(frecency.score("future"), 100.0);
+    }
+
+    #[test]
+    fn directory_weight() {
+        let mut frecency = frecency();
+
+        frecency.visit("here", Some(NOW - DAY * 2), 0);
+        frecency.visit("elsewhere", Some(NOW - DAY * 2), 0);
+        frecency.set_directory("here", 50.0);
+
+        assert_eq!(frecency.score("here"), 110.0);
+        assert_eq!(frecency.score("elsewhere"), 60.0);
+
+        let weights = Weights { directory: 0.0, ..Weights::default() };
+        let mut frecency = Frecency::at(weights, NOW);
+
+        frecency.visit("here", Some(NOW - DAY * 2), 0);
+        frecency.set_directory("here", 50.0);
+
+        assert_eq!(frecency.score("here"), 60.0);
+    }
+
+    #[test]
+    fn failure_demotion() {
+        let mut frecency = frecency();
+
+        for line in &["works", "flaky", "broken"] {
+            frecency.visit(line, Some(NOW - 60), 0);
+        }
+
+        frecency.set_failure_rate("flaky", 0.5);
+        frecency.set_failure_rate("broken", 1.0);
+
+        assert_eq!(frecency.score("works"), 100.0);
+        assert_eq!(frecency.score("flaky"), 75.0);
+        assert_eq!(frecency.score("broken"), 50.0);
+
+        let weights = Weights { failure: 0.0, ..Weights::default() };
+        let mut frecency = Frecency::at(weights, NOW);
+
+        frecency.visit("broken", Some(NOW - 60), 0);
+        frecency.set_failure_rate("broken", 1.0);
+
+        assert_eq!(frecency.score("broken"), 100.0);
+    }
+
+    #[test]
+    fn validation() {
+        assert!(Weights::default().validate().is_ok());
+        assert!(Weights { failure: 1.5, ..Weights::default() }.validate().is_err());
+        assert!(Weights { stale: -1.0, ..Weights::default() }.validate().is_err());
+        assert!(Weights { time_buckets: vec![(10, 1.0), (5, 1.0)], ..Weights::default() }.validate().is_err());
+    }
+}
@@ -0,0 +1,41 @@
+# bis2 integration for bash, load with: eval "$(bis2 init bash)"
+__bis2_search() {
+    local selected ret
+    bind '"\e[9999~": redraw-current-line'
+    selected=$(bis2 --print-only --query="$READLINE_LINE" </dev/tty)
+    ret=$?
+    # 3 means run the line straight away
+    (( ret == 0 || ret == 3 )) || return
+    READLINE_LINE=$selected
+    READLINE_POINT=${#READLINE_LINE}
+    (( ret == 3 )) && bind '"\e[9999~": accept-line'
+}
+
+# tell bis2 how each command went, so ones that usually fail rank lower
+__bis2_record() {
+    local ret=$? entry
+    entry=$(HISTTIMEFORMAT= builtin history 1)
+    [[ $entry =~ ^\ *([0-9]+)\*?\ +(.*)$ ]] || return $ret
+    # PS0 marks that something ran, and a new entry that it made it into history
+    if [[ -n $__bis2_start && ${BASH_REMATCH[1]} != "$__bis2_last" ]]; then
+        (bis2 record --exit-status "$ret" --duration "$((SECONDS - __bis2_start))" \
+            -- "${BASH_REMATCH[2]}" >/dev/null 2>&1 &)
+    fi
+    __bis2_last=${BASH_REMATCH[1]}
+    __bis2_start=
+    return $ret
+}
+
+# disable flow control so CTRL-S can move up the list
+[[ -t 0 ]] && stty -ixon
+
+# bind -x can't accept the line itself, so follow the search with a key
+# that the search binds to accept-line when it wants the line run
+bind -x '"\e[9998~": __bis2_search'
+bind '"\e[9999~": redraw-current-line'
+bind '"\C-r": "\e[9998~\e[9999~"'
+
+# PS0 is expanded just before a command runs, the substring hides the assignment
+__bis2_empty=
+PS0+='${__bis2_empty:$((__bis2_start = SECONDS, 0)):0}'
+PROMPT_COMMAND="__bis2_record${PROMPT_COMMAND:+; $PROMPT_COMMAND}"
@@ -0,0 +1,31 @@
+# bis2 integration for fish, load with: bis2 init fish | source
+function __bis2_search
+    bis2 --print-only --query=(commandline | string collect) </dev/tty | read -lz selected
+    set -l ret $pipestatus[1]
+    # 3 means run the line straight away
+    contains -- $ret 0 3
+    or return
+    set -q selected[1]
+    or set selected ''
+    commandline --replace -- $selected
+    if test $ret -eq 3
+        commandline --function execute
+    else
+        commandline --function repaint
+    end
+end
+
+# tell bis2 how each command went, so ones that usually fail rank lower
+function __bis2_record --on-event fish_postexec
+    set -l ret $status
+    string trim -- $argv[1] | string length -q
+    or return
+    command bis2 record --exit-status $ret --duration (math $CMD_DURATION / 1000) \
+        -- $argv[1] >/dev/null 2>&1 &
+    disown 2>/dev/null
+end
+
+bind \cr __bis2_search
+if bind --mode insert >/dev/null 2>&1
+    bind --mode insert \cr __bis2_search
+end
@@ -0,0 +1,933 @@
+// Copyright 2015 Jerome Rasky <jerome@rasky.co>
+//
+// Licensed under the Apache License, version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     <http://www.apache.org/licenses/LICENSE-2.0>
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
+// License for the specific language concerning governing permissions and
+// limitations under the License.
+
+// the completions store, shared by every shell running bis2
+use std::io::prelude::*;
+
+use std::collections::HashSet;
+use std::io::BufReader;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::os::unix::io::AsRawFd;
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::process;
+
+use serde_json::{Map, Value};
+use serde_json;
+use dirs;
+use libc;
+
+use types::{Change, Completions, Retention};
+use index::{self, Index};
+use journal;
+use scoring;
+use constants::*;
+
+// the last shape of the json completions, which are only read now to convert
+const STORE_VERSION: u64 = 3;
+
+type Migration = fn(Value) -> Result<Value, String>;
+
+// MIGRATIONS[n] turns a version n file into a version n + 1 one
+const MIGRATIONS: &[Migration] = &[
+    wrap_bare,
+    add_runs,
+    add_times,
+];
+
+// decode has already checked the version by the time this is read
+#[derive(Deserialize)]
+struct Envelope<T> {
+    completions: T,
+}
+
+#[derive(Debug)]
+pub enum FormatError {
+    // can't be made sense of, so it's safe to start over
+    Corrupt(String),
+    // written by a newer bis2, so leave it alone
+    Newer(u64),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FormatError::Corrupt(ref error) => write!(f, "{}", error),
+            FormatError::Newer(version) => write!(f, "version {} is newer than this bis2 understands", version),
+        }
+    }
+}
+
+// held while reading and writing back, so concurrent saves don't lose updates
+struct Lock {
+    file: File,
+}
+
+impl Lock {
+    fn acquire(path: &Path) -> io::Result<Lock> {
+        let file = OpenOptions::new().write(true).create(true).truncate(false).open(path)?;
+
+        while unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            let error = io::Error::last_os_error();
+
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+        }
+
+        Ok(Lock { file })
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        unsafe {
+            libc::flock(self.file.as_raw_fd(), libc::LOCK_UN);
+        }
+    }
+}
+
+// completions as the search sees them
+#[derive(Debug)]
+pub enum Scores {
+    // looked up a line at a time, under what the journal has changed since
+    Indexed(Index, Recent),
+    Loaded(Completions),
+}
+
+// the lines the journal changed, as they are now
+#[derive(Debug)]
+pub struct Recent {
+    lines: HashSet<String>,
+    completions: Completions,
+}
+
+impl Recent {
+    fn replay(index: &Index, changes: Vec<(u64, Change)>, retention: &Retention) -> Recent {
+        let mut recent = Recent {
+            lines: HashSet::new(),
+            completions: Completions::new(),
+        };
+
+        for (time, change) in changes {
+            if recent.lines.insert(change.line().to_string()) {
+                // start from what the index had
+                if let Some((paths, runs)) = index.get(change.line()) {
+                    recent.completions.insert(change.line().to_string(), paths, runs);
+                }
+            }
+
+            recent.completions.apply(change, retention, time);
+        }
+
+        recent
+    }
+
+    fn merge(self, completions: &mut Completions) {
+        for line in &self.lines {
+            completions.remove(line);
+        }
+
+        for (line, paths, runs) in self.completions.entries() {
+            completions.insert(line.to_string(), paths.to_vec(), runs.cloned());
+        }
+    }
+}
+
+impl Scores {
+    pub fn get_score(&self, line: &str, path: &Path, factor: f32, retention: &Retention, now: u64) -> f32 {
+        match *self {
+            Scores::Indexed(_, ref recent) if recent.lines.contains(line) => {
+                recent.completions.get_score(line, path, factor, retention, now)
+            }
+            Scores::Indexed(ref index, _) => index.get_score(line, path, factor, retention, now),
+            Scores::Loaded(ref completions) => completions.get_score(line, path, factor, retention, now),
+        }
+    }
+
+    pub fn failure_rate(&self, line: &str, retention: &Retention, now: u64) -> f32 {
+        match *self {
+            Scores::Indexed(_, ref recent) if recent.lines.contains(line) => {
+                recent.completions.failure_rate(line, retention, now)
+            }
+            Scores::Indexed(ref index, _) => index.failure_rate(line, retention, now),
+            Scores::Loaded(ref completions) => completions.failure_rate(line, retention, now),
+        }
+    }
+
+    // everything at once that hasn't expired, which is slow for a big index
+    pub fn completions(self, retention: &Retention, now: u64) -> Completions {
+        let mut completions = match self {
+            Scores::Indexed(index, recent) => {
+                let mut completions = index.load().unwrap_or_else(|error| {
+                    warn!("Ignoring completions store: {}", error);
+                    Completions::new()
+                });
+
+                recent.merge(&mut completions);
+                completions
+            }
+            Scores::Loaded(completions) => completions,
+        };
+
+        completions.expire(retention, now);
+        completions
+    }
+}
+
+// where the completions are kept
+pub struct Store {
+    // the index
+    path: PathBuf,
+    // what's changed since the index was written
+    journal: PathBuf,
+    // the json file from before the index
+    legacy: PathBuf,
+}
+
+// a file next to the completions, like ~/.bis2_completions.lock
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+fn invalid_data(error: FormatError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+impl Store {
+    pub fn home() -> Store {
+        Store::in_directory(&dirs::home_dir().unwrap_or_default())
+    }
+
+    pub fn in_directory(directory: &Path) -> Store {
+        Store {
+            path: directory.join(".bis2_completions.db"),
+            journal: directory.join(".bis2_completions.journal"),
+            legacy: directory.join(".bis2_completions"),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn legacy_path(&self) -> &Path {
+        &self.legacy
+    }
+
+    fn lock(&self) -> io::Result<Lock> {
+        Lock::acquire(&sibling(&self.legacy, ".lock"))
+    }
+
+    pub fn load(&self, retention: &Retention) -> Scores {
+        trace!("Store path: {:?}", self.path);
+
+        match Index::open(&self.path) {
+            Ok(Ok(index)) => {
+                trace!("Opened completions store with {} lines", index.len());
+
+                let changes = match journal::read(&self.journal, index.generation()) {
+                    Ok(Ok(changes)) => changes,
+                    Ok(Err(error)) => {
+                        // the next change sets a corrupt journal aside
+                        warn!("Ignoring completions journal: {}", error);
+                        Vec::new()
+                    }
+                    Err(error) => {
+                        warn!("Failed to read completions journal: {}", error);
+                        Vec::new()
+                    }
+                };
+
+                trace!("Replaying {} changes from the journal", changes.len());
+                let recent = Recent::replay(&index, changes, retention);
+
+                Scores::Indexed(index, recent)
+            }
+            Ok(Err(error)) => {
+                // the next save sets a corrupt store aside
+                warn!("Ignoring completions store: {}", error);
+                Scores::Loaded(Completions::new())
+            }
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => Scores::Loaded(self.load_legacy()),
+            Err(error) => {
+                warn!("Failed to open completions store: {}", error);
+                Scores::Loaded(Completions::new())
+            }
+        }
+    }
+
+    // the json file, until the next save or `bis2 migrate` converts it
+    fn load_legacy(&self) -> Completions {
+        trace!("Completions path: {:?}", self.legacy);
+
+        match read(&self.legacy) {
+            Ok(Some(Ok(completions))) => {
+                debug!("Read completions from json, they'll be indexed on the next save");
+                completions
+            }
+            Ok(Some(Err(error))) => {
+                // the next save sets a corrupt file aside
+                warn!("Ignoring completions file: {}", error);
+                Completions::new()
+            }
+            Ok(None) => {
+                trace!("No completions found");
+                Completions::new()
+            }
+            Err(error) => {
+                warn!("Failed to read completions file: {}", error);
+                Completions::new()
+            }
+        }
+    }
+
+    // remember what happened, by adding to the journal rather than rewriting the
+    // index, which only happens once the journal has grown big enough
+    pub fn record(&self, changes: Vec<Change>, retention: &Retention) -> io::Result<()> {
+        let _lock = self.lock()?;
+        let now = scoring::now();
+
+        let generation = match Index::open(&self.path) {
+            Ok(Ok(index)) => index.generation(),
+            // a missing, old or corrupt index is sorted out by compacting
+            _ => return self.compact(changes, retention, now).map(|_| ()),
+        };
+
+        // appending starts over on a journal it can't read, so keep that one first
+        if let Ok(Err(error @ FormatError::Corrupt(_))) = journal::read(&self.journal, generation) {
+            set_aside(&self.journal, error)?;
+        }
+
+        let length = journal::append(&self.journal, generation, now, &changes)?;
+
+        if length > COMPLETION_JOURNAL_SIZE {
+            debug!("Compacting {} bytes of journal", length);
+            self.compact(Vec::new(), retention, now)?;
+        }
+
+        Ok(())
+    }
+
+    // convert the json file now rather than on the next save, returning how many
+    // lines it had, or None if there's nothing to convert
+    pub fn migrate(&self, retention: &Retention) -> io::Result<Option<usize>> {
+        let _lock = self.lock()?;
+
+        if self.path.exists() || !self.legacy.exists() {
+            return Ok(None);
+        }
+
+        self.compact(Vec::new(), retention, scoring::now()).map(Some)
+    }
+
+    // fold the journal and the given changes into a new index, forgetting what's too
+    // old or no longer there, and return how many lines are left; the lock must be held
+    fn compact(&self, changes: Vec<Change>, retention: &Retention, now: u64) -> io::Result<usize> {
+        let (mut completions, generation, legacy) = match Index::open(&self.path) {
+            Ok(Ok(index)) => match index.load() {
+                Ok(mut completions) => {
+                    for (time, change) in self.read_journal(index.generation())? {
+                        completions.apply(change, retention, time);
+                    }
+
+                    (completions, index.generation(), false)
+                }
+                Err(error) => (self.start_over(error)?, index.generation(), false),
+            },
+            Ok(Err(error @ FormatError::Newer(_))) => return Err(invalid_data(error)),
+            Ok(Err(error)) => (self.start_over(error)?, 0, false),
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => {
+                self.discard_journal()?;
+                (self.read_legacy()?, 0, true)
+            }
+            Err(error) => return Err(error),
+        };
+
+        for change in changes {
+            completions.apply(change, retention, now);
+        }
+
+        completions.prune(retention, now);
+
+        write_atomic(&self.path, &index::encode(&completions, generation + 1))?;
+
+        // a crash before this is harmless, since the journal's for the old generation
+        self.discard_journal()?;
+
+        if legacy && self.legacy.exists() {
+            // keep it around for older versions of bis2, which won't see the index
+            let migrated = sibling(&self.legacy, ".migrated");
+            debug!("Moving converted completions to {:?}", migrated);
+            fs::rename(&self.legacy, &migrated)?;
+        }
+
+        Ok(completions.entries().len())
+    }
+
+    // what's changed since the given generation, which a newer bis2 may have written
+    fn read_journal(&self, generation: u64) -> io::Result<Vec<(u64, Change)>> {
+        match journal::read(&self.journal, generation)? {
+            Ok(changes) => Ok(changes),
+            Err(error @ FormatError::Newer(_)) => Err(invalid_data(error)),
+            Err(error) => {
+                set_aside(&self.journal, error)?;
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    // the journal went with the index, and mustn't be replayed onto its replacement
+    fn start_over<E: fmt::Display>(&self, error: E) -> io::Result<Completions> {
+        self.discard_journal()?;
+        set_aside(&self.path, error)
+    }
+
+    fn discard_journal(&self) -> io::Result<()> {
+        match fs::remove_file(&self.journal) {
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn read_legacy(&self) -> io::Result<Completions> {
+        let completions = match read(&self.legacy)? {
+            Some(Ok(completions)) => completions,
+            Some(Err(error @ FormatError::Newer(_))) => return Err(invalid_data(error)),
+            Some(Err(error)) => set_aside(&self.legacy, error)?,
+            None => Completions::new(),
+        };
+
+        Ok(completions)
+    }
+}
+
+// None if there's no file yet, and why if it can't be understood
+fn read(path: &Path) -> io::Result<Option<Result<Completions, FormatError>>> {
+    match File::open(path) {
+        Ok(file) => {
+            let decoded = serde_json::from_reader(BufReader::new(file))
+                .map_err(|error| FormatError::Corrupt(error.to_string()))
+                .and_then(decode);
+
+            Ok(Some(decoded))
+        }
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+fn decode(mut value: Value) -> Result<Completions, FormatError> {
+    let mut version = match value.get("version") {
+        Some(version) => version.as_u64().ok_or_else(|| FormatError::Corrupt(format!("bad version {}", version)))?,
+        // from before there were versions
+        None => 0,
+    };
+
+    if version > STORE_VERSION {
+        return Err(FormatError::Newer(version));
+    }
+
+    while version < STORE_VERSION {
+        debug!("Migrating completions from version {}", version);
+        value = MIGRATIONS[version as usize](value).map_err(FormatError::Corrupt)?;
+        version += 1;
+    }
+
+    let envelope: Envelope<Completions> = serde_json::from_value(value)
+        .map_err(|error| FormatError::Corrupt(error.to_string()))?;
+
+    Ok(envelope.completions)
+}
+
+// version 0 was the completions on their own
+fn wrap_bare(value: Value) -> Result<Value, String> {
+    let mut envelope = Map::new();
+    envelope.insert("version".into(), Value::from(1));
+    envelope.insert("completions".into(), value);
+    Ok(Value::Object(envelope))
+}
+
+// version 2 added what `bis2 record` learns about each command
+fn add_runs(mut value: Value) -> Result<Value, String> {
+    let envelope = value.as_object_mut().ok_or("expected an object")?;
+
+    match envelope.get_mut("completions").and_then(Value::as_object_mut) {
+        Some(completions) => completions.insert("runs".into(), Value::Object(Map::new())),
+        None => return Err("expected completions".into()),
+    };
+
+    envelope.insert("version".into(), Value::from(2));
+    Ok(value)
+}
+
+// version 3 added when things were last used, which we can only guess is now
+fn add_times(mut value: Value) -> Result<Value, String> {
+    let now = Value::from(scoring::now());
+    let envelope = value.as_object_mut().ok_or("expected an object")?;
+
+    {
+        let completions = envelope.get_mut("completions").and_then(Value::as_object_mut)
+            .ok_or("expected completions")?;
+
+        if let Some(info) = completions.get_mut("info").and_then(Value::as_object_mut) {
+            for paths in info.values_mut() {
+                for path in paths.as_array_mut().into_iter().flatten() {
+                    path.as_array_mut().ok_or("expected a directory and count")?.push(now.clone());
+                }
+            }
+        }
+
+        if let Some(runs) = completions.get_mut("runs").and_then(Value::as_object_mut) {
+            for runs in runs.values_mut() {
+                runs.as_object_mut().ok_or("expected runs")?.insert("time".into(), now.clone());
+            }
+        }
+    }
+
+    envelope.insert("version".into(), Value::from(3));
+    Ok(value)
+}
+
+// keep what's left of it around, and start over
+fn set_aside<E: fmt::Display>(path: &Path, error: E) -> io::Result<Completions> {
+    let corrupt = sibling(path, ".corrupt");
+    warn!("Completions store is corrupt, moving it to {:?}: {}", corrupt, error);
+    fs::rename(path, &corrupt)?;
+    Ok(Completions::new())
+}
+
+// readers see the old file or the new one, never half of one
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    // in the same directory, since rename can't cross filesystems
+    let temp = sibling(path, &format!(".{}.tmp", process::id()));
+
+    let result = write_file(&temp, path, data).and_then(|_| fs::rename(&temp, path));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+
+    result
+}
+
+fn write_file(temp: &Path, path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut file = File::create(temp)?;
+
+    if let Ok(metadata) = fs::metadata(path) {
+        // someone may have made it private
+        file.set_permissions(metadata.permissions())?;
+    }
+
+    file.write_all(data)?;
+
+    // make sure it's on disk before it replaces the old one
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use types::Runs;
+    use testing::Directory;
+
+    // what each version of the json file looked like
+    const VERSION_0: &str = r#"{"info":{"make":[["/src",2.0]],"ls":[["/",1.0],["/tmp",3.5]]}}"#;
+    const VERSION_1: &str = r#"{"version":1,"completions":{"info":{"make":[["/src",2.0]],"ls":[["/",1.0],["/tmp",3.5]]}}}"#;
+    const VERSION_2: &str = r#"{"version":2,"completions":{
+        "info":{"make":[["/src",2.0]],"ls":[["/",1.0],["/tmp",3.5]]},
+        "runs":{"make":{"count":4,"failures":1,"seconds":12.5,"directory":"/src"}}}}"#;
+    const VERSION_3: &str = r#"{"version":3,"completions":{
+        "info":{"make":[["/src",2.0,100]],"ls":[["/",1.0,200],["/tmp",3.5,300]]},
+        "runs":{"make":{"count":4,"failures":1,"seconds":12.5,"directory":"/src","time":400}}}}"#;
+
+    // the fixtures' contents, with everything that had no time given the one the migration ran at
+    fn expected(info_times: [u64; 3], runs_time: Option<u64>) -> Completions {
+        let mut completions = Completions::new();
+
+        let runs = runs_time.map(|time| Runs {
+            count: 4,
+            failures: 1,
+            seconds: 12.5,
+            directory: Some("/src".into()),
+            time,
+        });
+
+        completions.insert("make".into(), vec![("/src".into(), 2.0, info_times[0])], runs);
+        completions.insert("ls".into(), vec![("/".into(), 1.0, info_times[1]), ("/tmp".into(), 3.5, info_times[2])], None);
+        completions
+    }
+
+    fn decode_str(text: &str) -> (Result<Completions, FormatError>, u64) {
+        let now = scoring::now();
+        (decode(serde_json::from_str(text).unwrap()), now)
+    }
+
+    // migrations stamp things with the time they ran, which is now or a second later
+    fn decoded_at(text: &str, runs: bool) -> Completions {
+        let (decoded, now) = decode_str(text);
+        let decoded = decoded.unwrap();
+
+        (now..now + 2).map(|time| expected([time; 3], if runs { Some(time) } else { None }))
+            .find(|expected| *expected == decoded)
+            .unwrap_or_else(|| panic!("unexpected {:?}", decoded))
+    }
+
+    #[test]
+    fn version_0() {
+        decoded_at(VERSION_0, false);
+    }
+
+    #[test]
+    fn version_1() {
+        decoded_at(VERSION_1, false);
+    }
+
+    #[test]
+    fn version_2() {
+        decoded_at(VERSION_2, true);
+    }
+
+    #[test]
+    fn version_3() {
+        assert_eq!(decode_str(VERSION_3).0.unwrap(), expected([100, 200, 300], Some(400)));
+    }
+
+    #[test]
+    fn round_trip() {
+        let directory = Directory::new("round-trip");
+        let store = directory.store();
+
+        for (name, text) in [("0", VERSION_0), ("1", VERSION_1), ("2", VERSION_2), ("3", VERSION_3)].iter() {
+            let decoded = decode_str(text).0.unwrap();
+
+            fs::write(store.path(), index::encode(&decoded, 1)).unwrap();
+
+            // the fixtures are from long enough ago to have expired
+            let retention = Retention { max_age: u64::MAX, ..retention() };
+
+            assert_eq!(store.load(&retention).completions(&retention, scoring::now()), decoded, "version {}", name);
+        }
+    }
+
+    #[test]
+    fn newer_version() {
+        match decode_str(r#"{"version":4,"completions":{"something":"else"}}"#).0 {
+            Err(FormatError::Newer(4)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bad_versions() {
+        let texts = [
+            r#"{"version":"three","completions":{}}"#,
+            r#"{"version":-1,"completions":{}}"#,
+            r#"{"version":1}"#,
+            r#"{"version":2,"completions":{"info":{}}}"#,
+            r#"[1, 2, 3]"#,
+        ];
+
+        for text in texts.iter() {
+            match decode_str(text).0 {
+                Err(FormatError::Corrupt(_)) => {}
+                other => panic!("unexpected {:?} from {}", other, text),
+            }
+        }
+    }
+
+    fn retention() -> Retention {
+        Retention { forget_missing: false, ..Retention::default() }
+    }
+
+    fn choose(store: &Store, line: &str) -> io::Result<()> {
+        store.record(vec![Change::Chosen(line.into(), "/".into())], &retention())
+    }
+
+    fn lines(store: &Store) -> Vec<String> {
+        let completions = store.load(&retention()).completions(&retention(), scoring::now());
+        completions.entries().iter().map(|&(line, _, _)| line.to_string()).collect()
+    }
+
+    fn generation(store: &Store) -> u64 {
+        Index::open(store.path()).unwrap().unwrap().generation()
+    }
+
+    #[test]
+    fn updates_merge() {
+        let directory = Directory::new("updates-merge");
+        let store = directory.store();
+
+        choose(&store, "make").unwrap();
+        choose(&store, "ls").unwrap();
+
+        assert_eq!(lines(&store), vec!["ls", "make"]);
+    }
+
+    #[test]
+    fn garbage_is_set_aside() {
+        let directory = Directory::new("garbage");
+        let store = directory.store();
+
+        fs::write(store.path(), b"garbage").unwrap();
+        choose(&store, "make").unwrap();
+
+        assert_eq!(fs::read(sibling(store.path(), ".corrupt")).unwrap(), b"garbage");
+        assert_eq!(lines(&store), vec!["make"]);
+    }
+
+    #[test]
+    fn truncated_is_set_aside() {
+        let directory = Directory::new("truncated");
+        let store = directory.store();
+
+        choose(&store, "make").unwrap();
+        choose(&store, "ls").unwrap();
+
+        let data = fs::read(store.path()).unwrap();
+        fs::write(store.path(), &data[..data.len() - 10]).unwrap();
+
+        // which only compaction reads all of
+        store.compact(vec![Change::Chosen("cargo build".into(), "/".into())], &retention(), scoring::now()).unwrap();
+
+        assert!(sibling(store.path(), ".corrupt").exists());
+        assert_eq!(lines(&store), vec!["cargo build"]);
+    }
+
+    #[test]
+    fn newer_is_left_alone() {
+        let directory = Directory::new("newer");
+        let store = directory.store();
+
+        let mut data = b"BIS2".to_vec();
+        data.extend_from_slice(&99u32.to_le_bytes());
+        data.extend_from_slice(&[0; 16]);
+        fs::write(store.path(), &data).unwrap();
+
+        let error = choose(&store, "make").unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::read(store.path()).unwrap(), data);
+        assert!(!sibling(store.path(), ".corrupt").exists());
+    }
+
+    #[test]
+    fn legacy_is_converted() {
+        let directory = Directory::new("legacy");
+        let store = directory.store();
+
+        fs::write(store.legacy_path(), br#"{"info":{"make":[["/",2.0]]}}"#).unwrap();
+
+        assert_eq!(lines(&store), vec!["make"]);
+
+        choose(&store, "ls").unwrap();
+
+        assert_eq!(lines(&store), vec!["ls", "make"]);
+        assert!(!store.legacy_path().exists());
+        assert!(sibling(store.legacy_path(), ".migrated").exists());
+        assert_eq!(store.migrate(&retention()).unwrap(), None);
+    }
+
+    #[test]
+    fn migrate() {
+        let directory = Directory::new("migrate");
+        let store = directory.store();
+
+        assert_eq!(store.migrate(&retention()).unwrap(), None);
+
+        fs::write(store.legacy_path(), br#"{"info":{"make":[["/",2.0]],"ls":[["/",1.0]]}}"#).unwrap();
+
+        assert_eq!(store.migrate(&retention()).unwrap(), Some(2));
+        assert_eq!(lines(&store), vec!["ls", "make"]);
+    }
+
+    #[test]
+    fn corrupt_legacy_is_set_aside() {
+        let directory = Directory::new("corrupt-legacy");
+        let store = directory.store();
+
+        fs::write(store.legacy_path(), b"{not json").unwrap();
+        choose(&store, "make").unwrap();
+
+        assert_eq!(fs::read(sibling(store.legacy_path(), ".corrupt")).unwrap(), b"{not json");
+        assert_eq!(lines(&store), vec!["make"]);
+    }
+
+    #[test]
+    fn changes_are_journaled() {
+        let directory = Directory::new("journaled");
+        let store = directory.store();
+
+        // the first builds the index
+        choose(&store, "make").unwrap();
+        assert!(!store.journal.exists());
+
+        let index = fs::read(store.path()).unwrap();
+
+        choose(&store, "ls").unwrap();
+        store.record(vec![Change::Ran("make".into(), 2, 1.0, None), Change::Forgot("cd".into())], &retention()).unwrap();
+
+        assert_eq!(fs::read(store.path()).unwrap(), index);
+        assert!(store.journal.exists());
+
+        let scores = store.load(&retention());
+
+        assert!(scores.get_score("ls", Path::new("/"), 1.0, &retention(), scoring::now()) > 0.0);
+        assert_eq!(scores.failure_rate("make", &retention(), scoring::now()), 1.0);
+        assert_eq!(lines(&store), vec!["ls", "make"]);
+    }
+
+    #[test]
+    fn forgetting_is_journaled() {
+        let directory = Directory::new("forgetting");
+        let store = directory.store();
+
+        choose(&store, "make").unwrap();
+        choose(&store, "ls").unwrap();
+        store.record(vec![Change::Forgot("make".into())], &retention()).unwrap();
+
+        let scores = store.load(&retention());
+
+        assert_eq!(scores.get_score("make", Path::new("/"), 1.0, &retention(), scoring::now()), 0.0);
+        assert_eq!(lines(&store), vec!["ls"]);
+    }
+
+    #[test]
+    fn compacts_when_big() {
+        let directory = Directory::new("compacts");
+        let store = directory.store();
+
+        choose(&store, "make").unwrap();
+        assert_eq!(generation(&store), 1);
+
+        let long = "x".repeat(COMPLETION_JOURNAL_SIZE as usize);
+        choose(&store, &long).unwrap();
+
+        assert_eq!(generation(&store), 2);
+        assert!(!store.journal.exists());
+        assert_eq!(lines(&store), vec!["make".to_string(), long]);
+    }
+
+    #[test]
+    fn stale_journal_is_ignored() {
+        let directory = Directory::new("stale");
+        let store = directory.store();
+
+        choose(&store, "make").unwrap();
+        choose(&store, "make").unwrap();
+
+        let before = store.load(&retention()).completions(&retention(), scoring::now());
+        let journal = fs::read(&store.journal).unwrap();
+
+        store.compact(Vec::new(), &retention(), scoring::now()).unwrap();
+
+        // as if it crashed before the journal was removed
+        fs::write(&store.journal, &journal).unwrap();
+
+        assert_eq!(store.load(&retention()).completions(&retention(), scoring::now()), before);
+
+        // and the next change starts a new one
+        choose(&store, "ls").unwrap();
+
+        assert_eq!(lines(&store), vec!["ls", "make"]);
+        assert_eq!(journal::read(&store.journal, 2).unwrap().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn corrupt_journal_is_set_aside() {
+        let directory = Directory::new("corrupt-journal");
+        let store = directory.store();
+
+        choose(&store, "make").unwrap();
+        fs::write(&store.journal, b"garbage").unwrap();
+
+        assert_eq!(lines(&store), vec!["make"]);
+
+        store.compact(Vec::new(), &retention(), scoring::now()).unwrap();
+
+        assert_eq!(fs::read(sibling(&store.journal, ".corrupt")).unwrap(), b"garbage");
+        assert_eq!(lines(&store), vec!["make"]);
+    }
+
+    #[test]
+    fn corrupt_journal_is_set_aside_on_record() {
+        let directory = Directory::new("corrupt-journal-record");
+        let store = directory.store();
+
+        choose(&store, "make").unwrap();
+        choose(&store, "ls").unwrap();
+        fs::write(&store.journal, b"garbage").unwrap();
+
+        choose(&store, "cd").unwrap();
+
+        let mut recorded = lines(&store);
+        recorded.sort();
+
+        assert_eq!(fs::read(sibling(&store.journal, ".corrupt")).unwrap(), b"garbage");
+        assert_eq!(recorded, vec!["cd", "make"]);
+    }
+
+    #[test]
+    fn missing_directories_are_forgotten_on_compaction() {
+        let directory = Directory::new("missing");
+        let store = directory.store();
+        let retention = Retention::default();
+
+        store.record(vec![Change::Chosen("make".into(), "/".into())], &retention).unwrap();
+        store.record(vec![Change::Chosen("ls".into(), directory.join("gone"))], &retention).unwrap();
+
+        // nothing's checked until then
+        assert_eq!(lines(&store), vec!["ls", "make"]);
+
+        store.compact(Vec::new(), &retention, scoring::now()).unwrap();
+
+        assert_eq!(lines(&store), vec!["make"]);
+    }
+
+    #[test]
+    fn expired_entries_are_ignored_on_load() {
+        let directory = Directory::new("expired");
+        let store = directory.store();
+        let now = scoring::now();
+        let retention = Retention { max_age: 30, ..retention() };
+
+        let mut old = Completions::new();
+        old.add_completion("make".into(), "/".into(), &retention, now - 40 * 24 * 60 * 60);
+        old.record_run("make".into(), 1, 1.0, None, now - 40 * 24 * 60 * 60);
+        fs::write(store.path(), index::encode(&old, 1)).unwrap();
+
+        choose(&store, "ls").unwrap();
+
+        // only journaled, so the index still has it
+        assert!(store.journal.exists());
+        assert_eq!(Index::open(store.path()).unwrap().unwrap().len(), 1);
+
+        let scores = store.load(&retention);
+
+        assert_eq!(scores.get_score("make", Path::new("/"), 1.0, &retention, now), 0.0);
+        assert_eq!(scores.failure_rate("make", &retention, now), 0.0);
+        assert!(scores.get_score("ls", Path::new("/"), 1.0, &retention, now) > 0.0);
+
+        let completions = scores.completions(&retention, now);
+        let lines: Vec<&str> = completions.entries().iter().map(|&(line, _, _)| line).collect();
+
+        assert_eq!(lines, vec!["ls"]);
+    }
+}
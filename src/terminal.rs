@@ -13,38 +13,60 @@
 // limitations under the License.
 use std::io::prelude::*;
 
-use std::io::{Stdout, Stderr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::fs::OpenOptions;
 
 use std::io;
 
 use bis_c::*;
+use constants::*;
 
 pub struct Terminal {
-    output: Stdout,
-    error: Stderr,
+    output: Box<dyn Write>,
+    // where the chosen line goes
+    result: Box<dyn Write>,
+    fd: RawFd,
     rows: u16,
     cols: u16,
 }
 
 impl Drop for Terminal {
     fn drop(&mut self) {
-        restore_terminal();
+        // don't leave the shell with bracketed paste on
+        let _ = write!(self.output, "{}", PASTE_OFF);
+        let _ = self.output.flush();
+
+        restore_terminal(self.fd);
     }
 }
 
 impl Terminal {
-    pub fn create() -> Terminal {
-        let output = io::stdout();
+    pub fn create(print_only: bool) -> Terminal {
+        let (mut output, result, fd): (Box<dyn Write>, Box<dyn Write>, RawFd) = if print_only {
+            // stdout is probably being captured, so draw on the terminal itself
+            let tty = OpenOptions::new().read(true).write(true).open("/dev/tty")
+                .expect("Failed to open /dev/tty");
+            let fd = tty.as_raw_fd();
+
+            (Box::new(tty), Box::new(io::stdout()), fd)
+        } else {
+            let output = io::stdout();
+            let fd = output.as_raw_fd();
 
-        let error = io::stderr();
+            (Box::new(output), Box::new(io::stderr()), fd)
+        };
 
-        prepare_terminal();
+        prepare_terminal(fd);
 
-        let (rows, cols) = get_terminal_size();
+        let (rows, cols) = get_terminal_size(fd);
+
+        // have pastes arrive wrapped in markers, so we can tell them from typing
+        write!(output, "{}", PASTE_ON).expect("Failed to write output");
 
         Terminal {
             output,
-            error,
+            result,
+            fd,
             rows,
             cols,
         }
@@ -58,6 +80,17 @@ impl Terminal {
         self.cols
     }
 
+    // returns true if the size changed
+    pub fn update_size(&mut self) -> bool {
+        let (rows, cols) = get_terminal_size(self.fd);
+        let changed = (rows, cols) != (self.rows, self.cols);
+
+        self.rows = rows;
+        self.cols = cols;
+
+        changed
+    }
+
     pub fn output_str<T: AsRef<str>>(&mut self, s: T) {
         write!(self.output, "{}", s.as_ref()).expect("Failed to write output");
     }
@@ -67,6 +100,7 @@ impl Terminal {
     }
 
     pub fn insert_input<T: AsRef<str>>(&mut self, input: T) {
-        write!(self.error, "{}", input.as_ref()).expect("Failed to write to stderr")
+        write!(self.result, "{}", input.as_ref()).expect("Failed to write result");
+        self.result.flush().expect("Failed to flush result");
     }
 }
@@ -0,0 +1,51 @@
+// Copyright 2015 Jerome Rasky <jerome@rasky.co>
+//
+// Licensed under the Apache License, version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     <http://www.apache.org/licenses/LICENSE-2.0>
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
+// License for the specific language concerning governing permissions and
+// limitations under the License.
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use std::env;
+use std::fs;
+use std::process;
+
+use store::Store;
+
+// tests run side by side, so every directory gets a number of its own
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+// a directory of our own for each test, gone when it's done
+pub struct Directory(PathBuf);
+
+impl Directory {
+    pub fn new(name: &str) -> Directory {
+        let number = NEXT.fetch_add(1, Ordering::SeqCst);
+        let path = env::temp_dir().join(format!("bis2-test-{}-{}-{}", process::id(), number, name));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Directory(path)
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+
+    pub fn store(&self) -> Store {
+        Store::in_directory(&self.0)
+    }
+}
+
+impl Drop for Directory {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
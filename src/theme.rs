@@ -0,0 +1,166 @@
+// Copyright 2015 Jerome Rasky <jerome@rasky.co>
+//
+// Licensed under the Apache License, version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     <http://www.apache.org/licenses/LICENSE-2.0>
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
+// License for the specific language concerning governing permissions and
+// limitations under the License.
+
+// colors are either a terminal palette index or one of these names
+const COLOR_NAMES: [&str; 8] = ["black", "red", "green", "yellow", "blue", "magenta", "cyan", "white"];
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Color {
+    Index(u8),
+    Name(String),
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Style {
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+    pub bold: bool,
+    pub reverse: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Theme {
+    pub prompt: Style,
+    pub query: Style,
+    // the row the arrow points at
+    pub selected: Style,
+    // characters the query matched
+    pub highlight: Style,
+    // the history file a line came from
+    pub tag: Style,
+    // which matches are on screen, when they don't all fit
+    pub position: Style,
+}
+
+impl Color {
+    pub fn index(&self) -> Result<u8, String> {
+        match self {
+            Color::Index(index) => Ok(*index),
+            Color::Name(name) => {
+                // "bright-red" and friends are the second eight colors
+                let (base, offset) = match name.strip_prefix("bright-") {
+                    Some(base) => (base, 8),
+                    None => (name.as_str(), 0),
+                };
+
+                COLOR_NAMES.iter()
+                    .position(|&known| known == base)
+                    .map(|index| index as u8 + offset)
+                    .ok_or_else(|| format!("unknown color {:?}", name))
+            }
+        }
+    }
+}
+
+impl Style {
+    fn bold() -> Style {
+        Style { bold: true, ..Style::default() }
+    }
+
+    fn colored(color: &str, bold: bool) -> Style {
+        Style { fg: Some(Color::Name(color.into())), bold, ..Style::default() }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for color in self.fg.iter().chain(self.bg.iter()) {
+            color.index()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme {
+            prompt: Style::bold(),
+            query: Style::default(),
+            selected: Style::bold(),
+            highlight: Style::colored("yellow", true),
+            tag: Style::colored("cyan", false),
+            position: Style::default(),
+        }
+    }
+}
+
+impl Theme {
+    pub fn validate(&self) -> Result<(), String> {
+        self.prompt.validate()?;
+        self.query.validate()?;
+        self.selected.validate()?;
+        self.highlight.validate()?;
+        self.tag.validate()?;
+        self.position.validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json;
+
+    fn name(name: &str) -> Result<u8, String> {
+        Color::Name(name.into()).index()
+    }
+
+    fn style(json: &str) -> Result<Style, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    #[test]
+    fn named_colors() {
+        for (index, &known) in COLOR_NAMES.iter().enumerate() {
+            assert_eq!(name(known), Ok(index as u8));
+            assert_eq!(name(&format!("bright-{}", known)), Ok(index as u8 + 8));
+        }
+    }
+
+    #[test]
+    fn unknown_names() {
+        assert_eq!(name("mauve"), Err("unknown color \"mauve\"".into()));
+        assert!(name("Red").is_err());
+        assert!(name("bright-").is_err());
+        assert!(name("bright-bright-red").is_err());
+        assert!(name("").is_err());
+    }
+
+    #[test]
+    fn indices() {
+        assert_eq!(Color::Index(0).index(), Ok(0));
+        assert_eq!(Color::Index(255).index(), Ok(255));
+
+        let parsed = style(r#"{"fg": 255, "bg": "bright-blue"}"#).unwrap();
+        assert_eq!(parsed.fg.unwrap().index(), Ok(255));
+        assert_eq!(parsed.bg.unwrap().index(), Ok(12));
+
+        // past the end of the palette, or not a palette number at all
+        assert!(style(r#"{"fg": 256}"#).is_err());
+        assert!(style(r#"{"fg": -1}"#).is_err());
+        assert!(style(r#"{"fg": 1.5}"#).is_err());
+    }
+
+    #[test]
+    fn validation() {
+        assert!(Theme::default().validate().is_ok());
+
+        let theme = Theme { tag: style(r#"{"bg": "bright-mauve"}"#).unwrap(), ..Theme::default() };
+        assert_eq!(theme.validate(), Err("unknown color \"bright-mauve\"".into()));
+
+        assert!(style(r#"{"fg": "red", "italic": true}"#).is_err());
+    }
+}
@@ -13,229 +13,233 @@
 // limitations under the License.
 use std::io::prelude::*;
 
-use std::io::BufReader;
-use std::sync::{Arc, MutexGuard};
+use std::sync::Arc;
 use std::sync::mpsc::Sender;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::fs::File;
 use std::thread::JoinHandle;
-use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::os::unix::io::RawFd;
 use std::iter::FromIterator;
 
+use std::sync::mpsc;
 use std::env;
 use std::io;
 use std::thread;
 
-use serde_json;
-
 use flx::{SearchBase, LineInfo};
+use threadpool::ThreadPool;
 use dirs;
+use libc;
 
 use types::*;
+use store::{Scores, Store};
+use bis_c;
+use keys::{Decoder, Decoded};
+use keymap::{Action, Binding, Keymap};
+use history;
+use matching;
+use history::HistorySource;
+use scoring;
+use scoring::Frecency;
+use config::Config;
 use constants::*;
 
-pub fn start_threads(emit: Sender<Event>) -> (JoinHandle<()>, Arc<AtomicBool>) {
+pub fn start_threads(emit: Sender<Event>, config: &Config, keymap: Keymap) -> (JoinHandle<()>, Arc<AtomicBool>) {
     // start reading completions
     let completions_emit = emit.clone();
+    let use_completions = config.completions;
+    let retention = config.retention.clone();
     thread::spawn(move || {
-        read_completions(completions_emit);
+        let completions = if use_completions {
+            Store::home().load(&retention)
+        } else {
+            trace!("Completions disabled");
+            Scores::Loaded(Completions::new())
+        };
+
+        completions_emit.send(Event::CompletionsReady(completions)).unwrap();
     });
 
     // start the input thread
     let input_stop = Arc::new(AtomicBool::new(false));
     let stop = input_stop.clone();
-    let input_thread = thread::spawn(|| read_input(emit, stop));
+    let escape_timeout = config.escape_timeout;
+    let resize_emit = emit.clone();
+    let input_thread = thread::spawn(move || read_input(emit, stop, escape_timeout, keymap));
+
+    // this one never finishes, it goes away when we exit
+    let resize_fd = bis_c::watch_resize();
+    thread::spawn(move || read_resizes(resize_emit, resize_fd));
 
     (input_thread, input_stop)
 }
 
-fn read_completions(emit: Sender<Event>) {
-    let mut completions_path = dirs::home_dir().unwrap_or("".into());
-    completions_path.push(".bis2_completions");
-
-    trace!("Completions path: {:?}", completions_path);
-
-    let try_completions_file = File::open(completions_path);
-    if let Ok(completions_file) = try_completions_file {
-        trace!("Reading completions");
-        let completions = serde_json::from_reader(BufReader::new(completions_file))
-            .expect("Failed to read completions file");
-
-        trace!("Read completions");
-
-        emit.send(Event::CompletionsReady(completions)).unwrap();
+fn default_history_path() -> PathBuf {
+    let home = dirs::home_dir().unwrap_or_default();
+    let is_fish = env::var("SHELL")
+        .map(|shell| shell.ends_with("fish"))
+        .unwrap_or(false);
+
+    if is_fish {
+        // fish ignores HISTFILE and keeps its history under the XDG data directory
+        debug!("History file not found, defaulting to fish history");
+        let mut data = env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| home.join(".local/share"));
+        data.push("fish");
+        data.push("fish_history");
+        data
     } else {
-        trace!("No completions found");
-        emit.send(Event::CompletionsReady(Completions::new())).unwrap();
+        debug!("History file not found, defaulting to ~/.bash_history");
+        home.join(".bash_history")
     }
 }
 
-pub fn read_history(completions: MutexGuard<Completions>, emit: Sender<Event>) {
+pub fn history_sources(config: &Config) -> Vec<HistorySource> {
+    if let Some(ref specs) = config.history {
+        return specs.iter().map(|spec| HistorySource::parse(spec)).collect();
+    }
+
     let history_path = match env::var("HISTFILE") {
         Ok(path) => path.into(),
-        Err(env::VarError::NotPresent) => {
-            debug!("History file not found, defaulting to ~/.bash_history");
-            let mut home = dirs::home_dir().unwrap_or("".into());
-            home.push(".bash_history");
-            home
-        },
+        Err(env::VarError::NotPresent) => default_history_path(),
         Err(e) => {
             panic!("Failed to get history file path: {}", e);
         }
     };
 
-    trace!("History path: {:?}", history_path);
+    vec![HistorySource::new(history_path)]
+}
+
+pub fn read_history(completions: Scores, emit: Sender<Event>, pool: ThreadPool, config: Config) {
+    let sources = history_sources(&config);
+
+    trace!("History sources: {:?}", sources);
+
+    // read every source in parallel
+    let (loaded_emit, loaded) = mpsc::channel();
+
+    for (index, source) in sources.iter().enumerate() {
+        let source = source.clone();
+        let loaded_emit = loaded_emit.clone();
+
+        pool.execute(move || {
+            // the receiver outlives every sender
+            loaded_emit.send((index, source.load(index))).unwrap();
+        });
+    }
+
+    drop(loaded_emit);
+
+    let mut loaded_sources = vec![None; sources.len()];
+
+    for (index, result) in loaded.iter() {
+        match result {
+            Ok(entries) => loaded_sources[index] = Some(entries),
+            Err(error) => warn!("Failed to read history file {:?}: {}", sources[index].path, error),
+        }
+    }
 
     // try to get the current path
     let current_path = env::current_dir().ok();
 
     trace!("Current path: {:?}", current_path);
 
-    let input_file = BufReader::new(File::open(history_path).expect("Failed to open history file"));
-    let mut count = 0.0;
-    let mut set: HashMap<String, LineInfo> = HashMap::new();
-    let mut short: VecDeque<String> = VecDeque::new();
-
-    for maybe_line in input_file.lines() {
-        if let Ok(line) = maybe_line {
-            let item = set.entry(line.clone()).or_insert(LineInfo::new(line.as_str(), 0.0));
-            let old_count = item.get_factor();
-
-            let path_score = if let Some(ref path) = current_path {
-                // count the path score each time we see this line
-                completions.get_score(&line, path)
-            } else {
-                0.0
-            };
-
-            let maybe_index = short.iter().enumerate()
-                .find(|&(_, ref item)| line == **item)
-                .map(|(index, _)| index);
-
-            if let Some(index) = maybe_index {
-                short.remove(index);
-            }
+    let mut frecency = Frecency::new(config.scoring.clone());
+    let now = scoring::now();
+    let mut merged = vec![];
+
+    // the first source is the primary one, so it goes last to be the most recent
+    for entries in loaded_sources.into_iter().rev().flatten() {
+        let total = entries.len();
+        let mut last_timestamp = None;
 
-            short.push_back(line);
+        for (position, entry) in entries.into_iter().enumerate() {
+            trace!("History entry: {:?}", entry);
 
-            while short.len() > 10 {
-                short.pop_front();
+            if entry.timestamp.is_some() {
+                // untimestamped lines inherit the time of the command before them
+                last_timestamp = entry.timestamp;
             }
 
-            item.set_factor(old_count + count + path_score);
-            count += 1.0;
+            // every run counts towards how often a line is used
+            frecency.visit(&entry.line, last_timestamp, total - position - 1);
+
+            merged.push(entry);
         }
     }
 
-    // extract shortlist
-    let mut recent = vec![];
+    history::sort_by_time(&mut merged);
+
+    // the rest only depends on the line, so work it out once for each
+    let lines = history::dedupe(merged);
+
+    for item in lines.iter() {
+        frecency.set_failure_rate(&item.line, completions.failure_rate(&item.line, &config.retention, now));
 
-    for item in short.into_iter().rev() {
-        recent.push(item);
+        if let Some(ref path) = current_path {
+            // commands that mention files here are more likely to be wanted again
+            let file_score = item.paths.iter()
+                .filter(|item_path| path.join(item_path).exists())
+                .count() as f32 * HISTORY_PATH_SCORE;
+
+            frecency.set_directory(&item.line, completions.get_score(&item.line, path, config.completion_score_factor, &config.retention, now) + file_score);
+        }
     }
 
+    // only tag matches when there's more than one place they could come from
+    let tags = history::tags(&lines, &sources);
+
+    // extract shortlist, most recent first
+    let recent: Vec<String> = lines.iter().rev()
+        .take(config.match_number)
+        .map(|item| item.line.clone())
+        .collect();
+
     // send off recent history
-    emit.send(Event::HistoryReady(recent)).unwrap();
+    emit.send(Event::HistoryReady(recent, tags)).unwrap();
 
     // create the search base
-    let base = SearchBase::from_iter(set.into_iter().map(|(_, info)| info));
+    let base = SearchBase::from_iter(lines.into_iter().map(|item| {
+        let mut info = LineInfo::new(item.line.as_str(), 0.0);
+        info.set_factor(frecency.score(&item.line));
+        info
+    }));
 
     // if this fails, we can't search anything
     emit.send(Event::SearchReady(base)).unwrap();
 }
 
-fn read_input(emit: Sender<Event>, stop: Arc<AtomicBool>) {
+fn read_input(emit: Sender<Event>, stop: Arc<AtomicBool>, escape_timeout: u32, mut keymap: Keymap) {
     // this thread is joined on quit, so none of its sends should fail
-    let mut escape = None;
-
-    // escape sequence tree, assume ANSI
-    let mut control: HashMap<String, Option<Event>> = HashMap::default();
-    control.insert(format!("["), None);
-    control.insert(format!("[A"), Some(Event::KeyUp));
-    control.insert(format!("[B"), Some(Event::KeyDown));
+    let mut decoder = Decoder::default();
 
+    // read stdin unbuffered, so that polling it knows about every byte
     let chars = Chars {
-        inner: io::stdin()
+        inner: RawInput { fd: libc::STDIN_FILENO }
     };
 
     // read characters
     for maybe_chr in chars {
-        match maybe_chr {
+        let chr = match maybe_chr {
+            Ok(chr) => chr,
             Err(_) => {
                 error!("Failed to read input, quitting");
-                emit.send(Event::Quit(false)).unwrap();
+                emit.send(Event::Action(Action::Cancel)).unwrap();
                 break;
             }
-            Ok(ESC) => {
-                escape = Some(String::default());
-                trace!("Begin escape sequence");
-            }
-            Ok(chr) => {
-                match escape {
-                    None => {
-                        if chr.is_control() {
-                            if chr == EOT || chr == CTRL_C {
-                                // stop
-                                trace!("Got EOT or CTRL_C");
-                                emit.send(Event::Quit(false)).unwrap();
-                                break;
-                            } else if chr == CTRL_U {
-                                // clear query
-                                trace!("Got CTRL_U");
-                                emit.send(Event::Clear).unwrap();
-                            } else if chr == CTRL_R {
-                                // key up
-                                trace!("Got CTRL_R");
-                                emit.send(Event::KeyDown).unwrap();
-                            } else if chr == CTRL_S {
-                                // key down
-                                trace!("Got CTRL_S");
-                                emit.send(Event::KeyUp).unwrap();
-                            } else if chr == '\n' || chr == CR {
-                                // exit
-                                trace!("Got newline");
-                                emit.send(Event::Quit(true)).unwrap();
-                                break;
-                            } else if chr == ESC {
-                                // escape sequence
-                                trace!("Got ESC");
-                                escape = Some(format!(""));
-                            } else if chr == BSPC || chr == DEL {
-                                // backspace
-                                trace!("Got backspace");
-                                emit.send(Event::Backspace).unwrap();
-                            } else {
-                                debug!("Unknown control character {:?}", chr);
-                                emit.send(Event::Bell).unwrap();
-                            }
-                        } else {
-                            emit.send(Event::Input(chr)).unwrap();
-                        }
-                    }
-                    Some(mut seq) => {
-                        seq.push(chr);
-                        trace!("Sequence: {:?}", seq);
-                        match control.get(&seq) {
-                            None => {
-                                // no possible escape sequence like this
-                                emit.send(Event::Bell).unwrap();
-                                emit.send(Event::Input(chr)).unwrap();
-                                escape = None;
-                            }
-                            Some(&None) => {
-                                // keep going
-                                escape = Some(seq);
-                            }
-                            Some(&Some(ref event)) => {
-                                // send the appropriate event
-                                let cloned = event.maybe_clone().unwrap();
-                                emit.send(cloned).unwrap();
-                                escape = None;
-                            }
-                        }
-                    }
-                }
-            }
+        };
+
+        let mut decoded = decoder.feed(chr);
+
+        if decoder.is_pending() && !bis_c::wait_input(libc::STDIN_FILENO, escape_timeout) {
+            // a lone escape, rather than the start of a sequence
+            trace!("Escape timed out");
+            decoded = decoder.timeout();
+        }
+
+        if send_decoded(&emit, &mut keymap, decoded) {
+            break;
         }
 
         // check for requested stop
@@ -246,15 +250,118 @@ fn read_input(emit: Sender<Event>, stop: Arc<AtomicBool>) {
     }
 }
 
-pub fn start_query(emit: Sender<Event>, base: Arc<SearchBase>, query: String) {
-    let result = base.query(&query, MATCH_NUMBER);
-    let _ = emit.send(Event::Match(result.into_iter().map(|s| s.into()).collect(), query)).and_then(|_| {
+fn read_resizes(emit: Sender<Event>, fd: RawFd) {
+    let mut resizes = RawInput { fd };
+    let mut buf = [0; 64];
+
+    loop {
+        // several resizes at once only need one redraw
+        match resizes.read(&mut buf) {
+            Ok(0) => break,
+            Ok(_) => {
+                trace!("Terminal resized");
+                if emit.send(Event::Resize).is_err() {
+                    break;
+                }
+            }
+            Err(ref error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => {
+                warn!("Failed to read resizes: {}", error);
+                break;
+            }
+        }
+    }
+}
+
+// returns true if we sent a quit event
+fn send_decoded(emit: &Sender<Event>, keymap: &mut Keymap, decoded: Decoded) -> bool {
+    match decoded {
+        Decoded::Pending => {
+            trace!("Pending sequence");
+            false
+        }
+        Decoded::Unknown(sequence) => {
+            // swallow the whole sequence so none of it ends up in the query
+            debug!("Unknown escape sequence {:?}", sequence);
+            emit.send(Event::Bell).unwrap();
+            false
+        }
+        Decoded::Paste(text) => {
+            trace!("Got paste {:?}", text);
+            emit.send(Event::Paste(paste_text(&text))).unwrap();
+            false
+        }
+        Decoded::Key(press) => {
+            trace!("Got key {:?}", press);
+            match keymap.lookup(press) {
+                Binding::Action(action) => {
+                    emit.send(Event::Action(action)).unwrap();
+                    action.quits()
+                }
+                Binding::Input(chr) => {
+                    emit.send(Event::Input(chr)).unwrap();
+                    false
+                }
+                Binding::Handled => false,
+                Binding::Unbound => {
+                    debug!("Unbound key {:?}", press);
+                    emit.send(Event::Bell).unwrap();
+                    false
+                }
+            }
+        }
+    }
+}
+
+// the query is one line, so newlines become spaces and other controls go
+fn paste_text(text: &str) -> String {
+    let text = text.trim_end_matches(&['\n', CR][..]);
+    let mut line = String::with_capacity(text.len());
+    let mut last = None;
+
+    for chr in text.chars() {
+        match chr {
+            // count a crlf once
+            '\n' if last == Some(CR) => {}
+            '\n' | '\t' | CR => line.push(' '),
+            _ if chr.is_control() => {}
+            _ => line.push(chr),
+        }
+
+        last = Some(chr);
+    }
+
+    line
+}
+
+pub fn start_query(emit: Sender<Event>, base: Arc<SearchBase>, query: String, number: usize) {
+    let result = base.query(&query, number).into_iter().map(|line| {
+        (line.to_string(), matching::match_positions(line, &query))
+    }).collect();
+
+    if emit.send(Event::Match(result, query, number)).is_ok() {
         trace!("Finished query");
-        Ok(())
-    });
+    }
     // don't panic on fail send, events might be already closed
 }
 
+// stdin without the buffering std adds
+struct RawInput {
+    fd: RawFd,
+}
+
+impl io::Read for RawInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+
+        if read < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(read as usize)
+        }
+    }
+}
+
 // Copied from the standard library so I can use it in stable
 struct Chars<R> {
     inner: R
@@ -295,7 +402,7 @@ impl<R: io::Read> Iterator for Chars<R> {
     }
 }
 
-fn read_one_byte(reader: &mut Read) -> Option<Result<u8, ()>> {
+fn read_one_byte(reader: &mut dyn Read) -> Option<Result<u8, ()>> {
     let mut buf = [0];
     loop {
         return match reader.read(&mut buf) {
@@ -305,4 +412,27 @@ fn read_one_byte(reader: &mut Read) -> Option<Result<u8, ()>> {
             Err(_) => Some(Err(())),
         };
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paste_newlines() {
+        assert_eq!(paste_text("git log\n"), "git log");
+        assert_eq!(paste_text("git log\r\n\r\n"), "git log");
+        assert_eq!(paste_text("cd /tmp\nls"), "cd /tmp ls");
+        assert_eq!(paste_text("cd /tmp\r\nls"), "cd /tmp ls");
+        assert_eq!(paste_text("cd /tmp\rls"), "cd /tmp ls");
+        assert_eq!(paste_text("a\n\nb"), "a  b");
+    }
+
+    #[test]
+    fn paste_controls() {
+        assert_eq!(paste_text("a\tb"), "a b");
+        assert_eq!(paste_text("a\x1b[31mb\x07"), "a[31mb");
+        assert_eq!(paste_text("\x00ls\x7f"), "ls");
+        assert_eq!(paste_text("échö\n"), "échö");
+        assert_eq!(paste_text(""), "");
+    }
+}
@@ -0,0 +1,531 @@
+// Copyright 2015 Jerome Rasky <jerome@rasky.co>
+//
+// Licensed under the Apache License, version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     <http://www.apache.org/licenses/LICENSE-2.0>
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
+// License for the specific language concerning governing permissions and
+// limitations under the License.
+
+// terminfo parameterized strings, as described in terminfo(5)
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum TermStack {
+    // here for correctness
+    #[allow(dead_code)]
+    Str(String),
+    Int(isize),
+    Bool(bool),
+}
+
+// %[[:]flags][width[.precision]][doxXs]
+#[derive(Default)]
+struct Format {
+    left: bool,
+    sign: bool,
+    space: bool,
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl TermStack {
+    fn int(self) -> isize {
+        match self {
+            TermStack::Int(value) => value,
+            TermStack::Bool(truth) => truth as isize,
+            TermStack::Str(_) => {
+                error!("Expected a number, got a string");
+                0
+            }
+        }
+    }
+
+    fn truth(self) -> bool {
+        match self {
+            TermStack::Bool(truth) => truth,
+            TermStack::Int(value) => value != 0,
+            TermStack::Str(string) => !string.is_empty(),
+        }
+    }
+}
+
+// statics are the A-Z variables, which last between strings
+pub fn expand(sequence: &str, params: &[TermStack], statics: &mut [TermStack]) -> String {
+    let chars: Vec<char> = sequence.chars().collect();
+    let mut params: Vec<TermStack> = params.to_vec();
+    let mut dynamic = vec![TermStack::Int(0); 26];
+    let mut stack: Vec<TermStack> = vec![];
+    let mut result = String::default();
+    let mut idx = 0;
+
+    // missing parameters are zero
+    params.resize(9, TermStack::Int(0));
+
+    while idx < chars.len() {
+        let c = chars[idx];
+        idx += 1;
+
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+
+        let op = match chars.get(idx) {
+            Some(&op) => op,
+            None => {
+                error!("Escape at the end of the string");
+                break;
+            }
+        };
+        idx += 1;
+
+        match op {
+            '%' => result.push('%'),
+            'c' => {
+                let value = pop(&mut stack, op).int();
+                result.push(::std::char::from_u32(value as u32).unwrap_or('?'));
+            }
+            'p' => {
+                match chars.get(idx).and_then(|c| c.to_digit(10)) {
+                    Some(param) if param > 0 => stack.push(params[param as usize - 1].clone()),
+                    _ => error!("Bad parameter number: {:?}", chars.get(idx)),
+                }
+
+                idx += 1;
+            }
+            'P' | 'g' => {
+                let name = chars.get(idx).cloned().unwrap_or('?');
+                idx += 1;
+
+                let slot = match name {
+                    'a'..='z' => &mut dynamic[name as usize - 'a' as usize],
+                    'A'..='Z' => &mut statics[name as usize - 'A' as usize],
+                    _ => {
+                        error!("Bad variable name: {:?}", name);
+                        continue;
+                    }
+                };
+
+                if op == 'P' {
+                    *slot = pop(&mut stack, op);
+                } else {
+                    stack.push(slot.clone());
+                }
+            }
+            '\'' => {
+                // a character constant, like %'c'
+                let value = chars.get(idx).map_or(0, |&c| c as isize);
+                stack.push(TermStack::Int(value));
+                idx += 2;
+            }
+            '{' => {
+                // an integer constant, like %{8}
+                let digits: String = chars[idx..].iter().take_while(|&&c| c != '}').collect();
+                idx += digits.len() + 1;
+
+                match digits.parse() {
+                    Ok(value) => stack.push(TermStack::Int(value)),
+                    Err(_) => error!("Bad integer constant: {:?}", digits),
+                }
+            }
+            'l' => {
+                let length = match pop(&mut stack, op) {
+                    TermStack::Str(string) => string.chars().count(),
+                    _ => 0,
+                };
+                stack.push(TermStack::Int(length as isize));
+            }
+            '+' | '-' | '*' | '/' | 'm' | '&' | '|' | '^' | '=' | '<' | '>' | 'A' | 'O' => {
+                let b = pop(&mut stack, op);
+                let a = pop(&mut stack, op);
+
+                stack.push(match op {
+                    'A' => TermStack::Bool(a.truth() && b.truth()),
+                    'O' => TermStack::Bool(a.truth() || b.truth()),
+                    _ => binary(op, a.int(), b.int()),
+                });
+            }
+            '!' => {
+                let value = pop(&mut stack, op).truth();
+                stack.push(TermStack::Bool(!value));
+            }
+            '~' => {
+                let value = pop(&mut stack, op).int();
+                stack.push(TermStack::Int(!value));
+            }
+            'i' => {
+                // one-based coordinates for the first two parameters
+                for param in params.iter_mut().take(2) {
+                    if let TermStack::Int(ref mut value) = *param {
+                        *value += 1;
+                    }
+                }
+            }
+            // conditionals look like %? cond %t then %e else %;
+            '?' | ';' => {}
+            't' => {
+                if !pop(&mut stack, op).truth() {
+                    idx = skip_branch(&chars, idx, true);
+                }
+            }
+            'e' => {
+                // the branch we took is done
+                idx = skip_branch(&chars, idx, false);
+            }
+            _ => {
+                // anything else is a printf style format
+                let (format, conversion, next) = match parse_format(&chars, idx - 1) {
+                    Some(parsed) => parsed,
+                    None => {
+                        error!("Unknown escape character: {:?}", op);
+                        continue;
+                    }
+                };
+
+                idx = next;
+
+                let value = pop(&mut stack, op);
+                let text = match conversion {
+                    's' => match value {
+                        TermStack::Str(string) => format.string(&string),
+                        other => format.string(&other.int().to_string()),
+                    },
+                    _ => format.number(value.int(), conversion),
+                };
+
+                result.push_str(&text);
+            }
+        }
+    }
+
+    result
+}
+
+fn pop(stack: &mut Vec<TermStack>, op: char) -> TermStack {
+    stack.pop().unwrap_or_else(|| {
+        error!("Stack was empty for {:?}", op);
+        TermStack::Int(0)
+    })
+}
+
+fn binary(op: char, a: isize, b: isize) -> TermStack {
+    match op {
+        '+' => TermStack::Int(a.wrapping_add(b)),
+        '-' => TermStack::Int(a.wrapping_sub(b)),
+        '*' => TermStack::Int(a.wrapping_mul(b)),
+        '/' => TermStack::Int(if b == 0 { 0 } else { a / b }),
+        'm' => TermStack::Int(if b == 0 { 0 } else { a % b }),
+        '&' => TermStack::Int(a & b),
+        '|' => TermStack::Int(a | b),
+        '^' => TermStack::Int(a ^ b),
+        '=' => TermStack::Bool(a == b),
+        '<' => TermStack::Bool(a < b),
+        _ => TermStack::Bool(a > b),
+    }
+}
+
+// skip to the end of the conditional, or to its else branch
+fn skip_branch(chars: &[char], mut idx: usize, to_else: bool) -> usize {
+    let mut depth = 0;
+
+    while idx < chars.len() {
+        if chars[idx] != '%' {
+            idx += 1;
+            continue;
+        }
+
+        let op = chars.get(idx + 1).cloned();
+        idx += 2;
+
+        match op {
+            Some('?') => depth += 1,
+            Some(';') if depth == 0 => return idx,
+            Some(';') => depth -= 1,
+            Some('e') if depth == 0 && to_else => return idx,
+            // don't mistake a character constant for an operator
+            Some('\'') => idx += 2,
+            _ => {}
+        }
+    }
+
+    idx
+}
+
+// parse a format starting just after the %, returning where it ends
+fn parse_format(chars: &[char], mut idx: usize) -> Option<(Format, char, usize)> {
+    let mut format = Format::default();
+
+    // flags that are also operators need a colon first
+    let colon = chars.get(idx) == Some(&':');
+    if colon {
+        idx += 1;
+    }
+
+    while let Some(&flag) = chars.get(idx) {
+        match flag {
+            '-' if colon => format.left = true,
+            '+' if colon => format.sign = true,
+            ' ' => format.space = true,
+            '#' => format.alternate = true,
+            _ => break,
+        }
+
+        idx += 1;
+    }
+
+    // like printf, a leading zero on the width pads numbers with zeros
+    while chars.get(idx) == Some(&'0') {
+        format.zero = true;
+        idx += 1;
+    }
+
+    let (width, next) = parse_number(chars, idx);
+    format.width = width.unwrap_or(0);
+    idx = next;
+
+    if chars.get(idx) == Some(&'.') {
+        let (precision, next) = parse_number(chars, idx + 1);
+        format.precision = Some(precision.unwrap_or(0));
+        idx = next;
+    }
+
+    match chars.get(idx) {
+        Some(&conversion) if "doxXs".contains(conversion) => Some((format, conversion, idx + 1)),
+        _ => None,
+    }
+}
+
+fn parse_number(chars: &[char], start: usize) -> (Option<usize>, usize) {
+    let digits: String = chars[start..].iter().take_while(|c| c.is_ascii_digit()).collect();
+    (digits.parse().ok(), start + digits.len())
+}
+
+impl Format {
+    fn number(&self, value: isize, conversion: char) -> String {
+        // like C, only %d is signed
+        let unsigned = value as i32 as u32;
+        let mut digits = match conversion {
+            'o' => format!("{:o}", unsigned),
+            'x' => format!("{:x}", unsigned),
+            'X' => format!("{:X}", unsigned),
+            _ => value.unsigned_abs().to_string(),
+        };
+
+        if let Some(precision) = self.precision {
+            if precision == 0 && value == 0 {
+                digits.clear();
+            }
+
+            while digits.len() < precision {
+                digits.insert(0, '0');
+            }
+        }
+
+        let mut prefix = String::new();
+
+        match conversion {
+            'd' if value < 0 => prefix.push('-'),
+            'd' if self.sign => prefix.push('+'),
+            'd' if self.space => prefix.push(' '),
+            'o' if self.alternate && !digits.starts_with('0') => prefix.push('0'),
+            'x' if self.alternate && value != 0 => prefix.push_str("0x"),
+            'X' if self.alternate && value != 0 => prefix.push_str("0X"),
+            _ => {}
+        }
+
+        if self.zero && !self.left && self.precision.is_none() {
+            while prefix.len() + digits.len() < self.width {
+                digits.insert(0, '0');
+            }
+        }
+
+        self.pad(prefix + &digits)
+    }
+
+    fn string(&self, value: &str) -> String {
+        match self.precision {
+            Some(precision) => self.pad(value.chars().take(precision).collect()),
+            None => self.pad(value.to_string()),
+        }
+    }
+
+    fn pad(&self, text: String) -> String {
+        let length = text.chars().count();
+
+        if length >= self.width {
+            return text;
+        }
+
+        let mut result = String::new();
+
+        if self.left {
+            result.push_str(&text);
+        }
+
+        for _ in length..self.width {
+            result.push(' ');
+        }
+
+        if !self.left {
+            result.push_str(&text);
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::TermStack::*;
+
+    // xterm-256color, as infocmp prints them
+    const XTERM_CUP: &str = "\x1b[%i%p1%d;%p2%dH";
+    const XTERM_SETAF: &str = "\x1b[%?%p1%{8}%<%t3%p1%d%e%p1%{16}%<%t9%p1%{8}%-%d%e38;5;%p1%d%;m";
+    const XTERM_SETAB: &str = "\x1b[%?%p1%{8}%<%t4%p1%d%e%p1%{16}%<%t10%p1%{8}%-%d%e48;5;%p1%d%;m";
+
+    fn run(sequence: &str, params: &[TermStack]) -> String {
+        expand(sequence, params, &mut vec![Int(0); 26])
+    }
+
+    #[test]
+    fn cursor_address() {
+        assert_eq!(run(XTERM_CUP, &[Int(0), Int(0)]), "\x1b[1;1H");
+        assert_eq!(run(XTERM_CUP, &[Int(4), Int(9)]), "\x1b[5;10H");
+    }
+
+    #[test]
+    fn cursor_movement() {
+        assert_eq!(run("\x1b[%p1%dA", &[Int(3)]), "\x1b[3A");
+        assert_eq!(run("\x1b[%p1%dD", &[Int(12)]), "\x1b[12D");
+        // missing parameters are zero
+        assert_eq!(run("\x1b[%p1%dD", &[]), "\x1b[0D");
+    }
+
+    #[test]
+    fn colors() {
+        assert_eq!(run(XTERM_SETAF, &[Int(1)]), "\x1b[31m");
+        assert_eq!(run(XTERM_SETAF, &[Int(7)]), "\x1b[37m");
+        assert_eq!(run(XTERM_SETAF, &[Int(8)]), "\x1b[90m");
+        assert_eq!(run(XTERM_SETAF, &[Int(15)]), "\x1b[97m");
+        assert_eq!(run(XTERM_SETAF, &[Int(16)]), "\x1b[38;5;16m");
+        assert_eq!(run(XTERM_SETAF, &[Int(208)]), "\x1b[38;5;208m");
+
+        assert_eq!(run(XTERM_SETAB, &[Int(4)]), "\x1b[44m");
+        assert_eq!(run(XTERM_SETAB, &[Int(12)]), "\x1b[104m");
+        assert_eq!(run(XTERM_SETAB, &[Int(255)]), "\x1b[48;5;255m");
+    }
+
+    #[test]
+    fn eight_colors() {
+        // xterm's, which only has the first eight
+        let setaf = "\x1b[3%p1%dm";
+
+        assert_eq!(run(setaf, &[Int(2)]), "\x1b[32m");
+    }
+
+    #[test]
+    fn constants() {
+        assert_eq!(run("%'A'%c", &[]), "A");
+        assert_eq!(run("%'0'%p1%+%c", &[Int(7)]), "7");
+        assert_eq!(run("%{42}%d", &[]), "42");
+        assert_eq!(run("%{-3}%d", &[]), "-3");
+        assert_eq!(run("%%", &[]), "%");
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(run("%p1%p2%-%d", &[Int(10), Int(3)]), "7");
+        assert_eq!(run("%p1%p2%*%d", &[Int(6), Int(7)]), "42");
+        assert_eq!(run("%p1%p2%/%d", &[Int(7), Int(2)]), "3");
+        assert_eq!(run("%p1%p2%m%d", &[Int(7), Int(2)]), "1");
+        assert_eq!(run("%p1%p2%/%d", &[Int(7), Int(0)]), "0");
+        assert_eq!(run("%p1%p2%&%d", &[Int(6), Int(3)]), "2");
+        assert_eq!(run("%p1%p2%|%d", &[Int(6), Int(3)]), "7");
+        assert_eq!(run("%p1%p2%^%d", &[Int(6), Int(3)]), "5");
+        assert_eq!(run("%p1%~%d", &[Int(0)]), "-1");
+        assert_eq!(run("%p1%!%d", &[Int(0)]), "1");
+        assert_eq!(run("%p1%p2%A%d", &[Int(1), Int(0)]), "0");
+        assert_eq!(run("%p1%p2%O%d", &[Int(1), Int(0)]), "1");
+    }
+
+    #[test]
+    fn variables() {
+        let mut statics = vec![Int(0); 26];
+
+        // statics last between strings
+        assert_eq!(expand("%p1%PA%gA%d", &[Int(5)], &mut statics), "5");
+        assert_eq!(expand("%gA%d", &[], &mut statics), "5");
+
+        // dynamics don't
+        assert_eq!(expand("%p1%Pa%ga%d", &[Int(9)], &mut statics), "9");
+        assert_eq!(expand("%ga%d", &[], &mut statics), "0");
+
+        // and the two don't share names
+        assert_eq!(expand("%p1%Pb%gB%d", &[Int(3)], &mut statics), "0");
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(run("[%p1%:-5d]", &[Int(42)]), "[42   ]");
+        assert_eq!(run("[%p1%5d]", &[Int(42)]), "[   42]");
+        assert_eq!(run("[%p1%05d]", &[Int(42)]), "[00042]");
+        assert_eq!(run("[%p1%05d]", &[Int(-42)]), "[-0042]");
+        assert_eq!(run("[%p1%:+d]", &[Int(42)]), "[+42]");
+        assert_eq!(run("[%p1% d]", &[Int(42)]), "[ 42]");
+        assert_eq!(run("[%p1%.3d]", &[Int(7)]), "[007]");
+        assert_eq!(run("%p1%x", &[Int(255)]), "ff");
+        assert_eq!(run("%p1%X", &[Int(255)]), "FF");
+        assert_eq!(run("%p1%#x", &[Int(255)]), "0xff");
+        assert_eq!(run("%p1%#x", &[Int(0)]), "0");
+        assert_eq!(run("%p1%o", &[Int(8)]), "10");
+        assert_eq!(run("%p1%#o", &[Int(8)]), "010");
+        assert_eq!(run("%p1%4x", &[Int(10)]), "   a");
+    }
+
+    #[test]
+    fn strings() {
+        let word = &[Str("terminal".into())];
+
+        assert_eq!(run("%p1%s", word), "terminal");
+        assert_eq!(run("%p1%.4s", word), "term");
+        assert_eq!(run("[%p1%:-10.4s]", word), "[term      ]");
+        assert_eq!(run("%p1%l%d", word), "8");
+        assert_eq!(run("%p1%s", &[Int(12)]), "12");
+    }
+
+    #[test]
+    fn conditionals() {
+        let sequence = "%?%p1%{1}%=%tone%e%p1%{2}%=%ttwo%eother%;";
+
+        assert_eq!(run(sequence, &[Int(1)]), "one");
+        assert_eq!(run(sequence, &[Int(2)]), "two");
+        assert_eq!(run(sequence, &[Int(3)]), "other");
+        assert_eq!(run("%?%p1%tyes%;", &[Int(0)]), "");
+    }
+
+    #[test]
+    fn nested_conditionals() {
+        let sequence = "<%?%p1%t%?%p2%tab%eaB%;%e%?%p2%tAb%eAB%;%;>";
+
+        assert_eq!(run(sequence, &[Int(1), Int(1)]), "<ab>");
+        assert_eq!(run(sequence, &[Int(1), Int(0)]), "<aB>");
+        assert_eq!(run(sequence, &[Int(0), Int(1)]), "<Ab>");
+        assert_eq!(run(sequence, &[Int(0), Int(0)]), "<AB>");
+    }
+
+    #[test]
+    fn character_constants_in_skipped_branches() {
+        // a skipped %';' mustn't end the conditional early
+        assert_eq!(run("%?%p1%t%';'%c%eno%;!", &[Int(0)]), "no!");
+        assert_eq!(run("%?%p1%t%';'%c%eno%;!", &[Int(1)]), ";!");
+    }
+}
@@ -12,101 +12,375 @@
 // License for the specific language concerning governing permissions and
 // limitations under the License.
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::cmp::Ordering;
 
 use flx::SearchBase;
 
+use keymap::Action;
+use store::Scores;
 use constants::*;
 
+const DAY: f32 = 60.0 * 60.0 * 24.0;
+
+// a directory a line was chosen in, its count as of the time, and the time
+pub type Visit = (PathBuf, f32, u64);
+
 // serde types
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(PartialEq, Debug, Serialize, Deserialize)]
 pub struct Completions {
-    // Map<Line, Vec<Path>>
-    info: HashMap<String, Vec<(PathBuf, f32)>>
+    // Map<Line, Vec<Visit>>
+    info: HashMap<String, Vec<Visit>>,
+    // how commands went, from `bis2 record`
+    runs: HashMap<String, Runs>,
+}
+
+#[derive(Default, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Runs {
+    pub count: u32,
+    pub failures: u32,
+    // total time spent running it
+    pub seconds: f64,
+    // where it last ran
+    pub directory: Option<PathBuf>,
+    // when it last ran
+    pub time: u64,
+}
+
+// something that happened to a line, kept in the journal until the next compaction
+#[derive(Clone, PartialEq, Debug)]
+pub enum Change {
+    // picked in the given directory
+    Chosen(String, PathBuf),
+    // run by the shell, with its exit status, how long it took, and where
+    Ran(String, i32, f64, Option<PathBuf>),
+    // hidden from the matches
+    Forgot(String),
+}
+
+impl Change {
+    pub fn line(&self) -> &str {
+        match *self {
+            Change::Chosen(ref line, _) | Change::Ran(ref line, _, _, _) | Change::Forgot(ref line) => line,
+        }
+    }
+}
+
+// how long completions are remembered
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retention {
+    // days for a directory's count to halve
+    pub half_life: f32,
+    // directories kept for each line, the ones that count most
+    pub max_directories: usize,
+    // days unused before a directory or recorded command is forgotten
+    pub max_age: u64,
+    // forget directories that no longer exist
+    pub forget_missing: bool,
 }
 
 #[derive(Debug)]
 pub enum Event {
-    CompletionsReady(Completions),
-    HistoryReady(Vec<String>),
+    CompletionsReady(Scores),
+    // recent lines, and the source tags for every line
+    HistoryReady(Vec<String>, HashMap<String, String>),
     SearchReady(SearchBase),
     Input(char),
-    Match(Vec<String>, String),
-    Quit(bool),
-    KeyUp,
-    KeyDown,
-    Clear,
-    Backspace,
+    // matching lines with the positions of matched characters, the query,
+    // and how many matches were asked for
+    Match(Vec<(String, Vec<usize>)>, String, usize),
+    Action(Action),
+    Paste(String),
+    // the terminal changed size
+    Resize,
     Bell,
 }
 
+impl Runs {
+    // the share of runs that failed, unless it's been too long to remember
+    pub fn failure_rate(&self, retention: &Retention, now: u64) -> f32 {
+        if self.count > 0 && !retention.expired(self.time, now) {
+            self.failures as f32 / self.count as f32
+        } else {
+            0.0
+        }
+    }
+}
+
+// directories that share more of the current one's path count for more
+pub fn directory_score<'a, I>(paths: I, path: &Path, retention: &Retention, now: u64) -> f32
+    where I: Iterator<Item = (&'a Path, f32, u64)>
+{
+    let path_count = path.components().count() as f32;
+
+    paths.filter(|&(_, _, time)| !retention.expired(time, now)).map(|(score_path, count, time)| {
+        let base_count = path.components()
+            .zip(score_path.components())
+            .take_while(|&(path_component, score_component)| path_component == score_component)
+            .count() as f32;
+
+        let total_count = (2.0 * base_count) - path_count;
+
+        if total_count > 0.0 {
+            total_count * retention.decay(count, time, now)
+        } else {
+            0.0
+        }
+    }).sum()
+}
+
+impl Default for Retention {
+    fn default() -> Retention {
+        Retention {
+            half_life: COMPLETION_HALF_LIFE,
+            max_directories: COMPLETION_MAX_DIRECTORIES,
+            max_age: COMPLETION_MAX_AGE,
+            forget_missing: true,
+        }
+    }
+}
+
+impl Retention {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.half_life.is_finite() || self.half_life <= 0.0 {
+            return Err("retention half_life must be a positive number of days".into());
+        }
+
+        if self.max_directories == 0 || self.max_age == 0 {
+            return Err("retention limits must be at least 1".into());
+        }
+
+        Ok(())
+    }
+
+    // what a count from the given time is worth now
+    pub fn decay(&self, count: f32, time: u64, now: u64) -> f32 {
+        let age = now.saturating_sub(time) as f32 / DAY;
+        count * 0.5f32.powf(age / self.half_life)
+    }
+
+    fn expired(&self, time: u64, now: u64) -> bool {
+        // a huge max_age means forever, not an overflow
+        now.saturating_sub(time) > self.max_age.saturating_mul(DAY as u64)
+    }
+}
+
 impl Completions {
     pub fn new() -> Completions {
         Completions {
-            info: HashMap::new()
+            info: HashMap::new(),
+            runs: HashMap::new(),
         }
     }
 
-    pub fn get_score(&self, line: &String, path: &PathBuf) -> f32 {
-        let path_count = path.components().count() as f32;
+    pub fn len(&self) -> usize {
+        self.info.len()
+    }
+
+    pub fn recorded(&self) -> usize {
+        self.runs.len()
+    }
+
+    pub fn directories(&self) -> usize {
+        self.info.values().map(|paths| paths.len()).sum()
+    }
 
+    pub fn get_score(&self, line: &str, path: &Path, factor: f32, retention: &Retention, now: u64) -> f32 {
         if let Some(paths) = self.info.get(line) {
-            paths.iter().map(|&(ref score_path, count)| {
-                let base_count = path.components()
-                    .zip(score_path.components())
-                    .take_while(|&(path_component, score_component)| path_component == score_component)
-                    .count() as f32;
-
-                let total_count = (2.0 * base_count) - path_count;
-
-                if total_count > 0.0 {
-                    total_count * count
-                } else {
-                    0.0
-                }
-            }).sum::<f32>() * COMPLETION_SCORE_FACTOR
+            let paths = paths.iter().map(|&(ref score_path, count, time)| (score_path.as_path(), count, time));
+            directory_score(paths, path, retention, now) * factor
         } else {
             0.0
         }
     }
 
-    pub fn add_completion(&mut self, line: String, path: PathBuf) {
-        let entry = self.info.entry(line).or_insert(vec![]);
-        let mut count = 1.0;
-        let mut place = None;
+    // every line we know something about
+    pub fn entries(&self) -> Vec<(&str, &[Visit], Option<&Runs>)> {
+        let mut lines: Vec<&String> = self.info.keys().chain(self.runs.keys()).collect();
+        lines.sort();
+        lines.dedup();
 
-        for (idx, &(ref entry_path, entry_count)) in entry.iter().enumerate() {
-            if &path == entry_path {
-                count = entry_count + 1.0;
-                place = Some(idx);
-                break;
+        lines.into_iter().map(|line| {
+            let paths = self.info.get(line).map_or(&[][..], |paths| &paths[..]);
+            (line.as_str(), paths, self.runs.get(line))
+        }).collect()
+    }
+
+    pub fn insert(&mut self, line: String, paths: Vec<Visit>, runs: Option<Runs>) {
+        if let Some(runs) = runs {
+            self.runs.insert(line.clone(), runs);
+        }
+
+        if !paths.is_empty() {
+            self.info.insert(line, paths);
+        }
+    }
+
+    pub fn add_completion(&mut self, line: String, path: PathBuf, retention: &Retention, now: u64) {
+        let entry = self.info.entry(line).or_default();
+
+        match entry.iter_mut().find(|&&mut (ref entry_path, _, _)| *entry_path == path) {
+            Some(&mut (_, ref mut count, ref mut time)) => {
+                // bring the old count up to date before adding to it
+                *count = retention.decay(*count, *time, now) + 1.0;
+                *time = now;
+            }
+            None => entry.push((path, 1.0, now)),
+        }
+    }
+
+    // forget what's too old, which lookups already ignore
+    pub fn expire(&mut self, retention: &Retention, now: u64) {
+        for paths in self.info.values_mut() {
+            paths.retain(|&(_, _, time)| !retention.expired(time, now));
+        }
+
+        self.info.retain(|_, paths| !paths.is_empty());
+        self.runs.retain(|_, runs| !retention.expired(runs.time, now));
+    }
+
+    // forget what's too old or no longer there, run before saving
+    pub fn prune(&mut self, retention: &Retention, now: u64) {
+        self.expire(retention, now);
+
+        // many lines share directories, so only check each once
+        let mut exists: HashMap<PathBuf, bool> = HashMap::new();
+
+        for paths in self.info.values_mut() {
+            if retention.forget_missing {
+                paths.retain(|(path, _, _)| *exists.entry(path.clone()).or_insert_with(|| path.is_dir()));
+            }
+
+            if paths.len() > retention.max_directories {
+                paths.sort_by(|a, b| {
+                    let (a, b) = (retention.decay(a.1, a.2, now), retention.decay(b.1, b.2, now));
+                    b.partial_cmp(&a).unwrap_or(Ordering::Equal)
+                });
+
+                paths.truncate(retention.max_directories);
             }
         }
 
-        entry.push((path, count));
+        self.info.retain(|_, paths| !paths.is_empty());
+    }
+
+    pub fn record_run(&mut self, line: String, status: i32, seconds: f64, directory: Option<PathBuf>, now: u64) {
+        let runs = self.runs.entry(line).or_default();
 
-        if let Some(idx) = place {
-            // swap out with the old count
-            entry.swap_remove(idx);
+        runs.count += 1;
+        runs.seconds += seconds;
+        runs.directory = directory;
+        runs.time = now;
+
+        // above 128 it was killed by a signal, usually CTRL-C, which isn't the command's fault
+        if status > 0 && status <= 128 {
+            runs.failures += 1;
         }
     }
-}
 
-impl Event {
-    pub fn maybe_clone(&self) -> Option<Event> {
-        use ::types::Event::*;
-        match self {
-            &CompletionsReady(_) => None,
-            &HistoryReady(_) => None,
-            &SearchReady(_) => None,
-            &Input(ref chr) => Some(Input(*chr)),
-            &Match(ref matches, ref query) => Some(Match(matches.clone(), query.clone())),
-            &Quit(ref success) => Some(Quit(*success)),
-            &KeyUp => Some(KeyUp),
-            &KeyDown => Some(KeyDown),
-            &Clear => Some(Clear),
-            &Backspace => Some(Backspace),
-            &Bell => Some(Bell),
+    pub fn failure_rate(&self, line: &str, retention: &Retention, now: u64) -> f32 {
+        self.runs.get(line).map_or(0.0, |runs| runs.failure_rate(retention, now))
+    }
+
+    pub fn apply(&mut self, change: Change, retention: &Retention, now: u64) {
+        match change {
+            Change::Chosen(line, path) => self.add_completion(line, path, retention, now),
+            Change::Ran(line, status, seconds, directory) => self.record_run(line, status, seconds, directory, now),
+            Change::Forgot(line) => {
+                self.remove(&line);
+            }
         }
     }
+
+    // returns true if we knew about the line
+    pub fn remove(&mut self, line: &str) -> bool {
+        let runs = self.runs.remove(line).is_some();
+        self.info.remove(line).is_some() || runs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_600_000_000;
+    const DAYS: u64 = 60 * 60 * 24;
+
+    #[test]
+    fn decay_halves() {
+        let retention = Retention { half_life: 10.0, ..Retention::default() };
+
+        assert_eq!(retention.decay(4.0, NOW, NOW), 4.0);
+        assert_eq!(retention.decay(4.0, NOW - 10 * DAYS, NOW), 2.0);
+        assert_eq!(retention.decay(4.0, NOW - 20 * DAYS, NOW), 1.0);
+        // from the future counts as now
+        assert_eq!(retention.decay(4.0, NOW + DAYS, NOW), 4.0);
+    }
+
+    #[test]
+    fn expiry() {
+        let retention = Retention { max_age: 30, ..Retention::default() };
+
+        assert!(!retention.expired(NOW - 30 * DAYS, NOW));
+        assert!(retention.expired(NOW - 31 * DAYS, NOW));
+
+        let forever = Retention { max_age: u64::MAX, ..Retention::default() };
+
+        assert!(forever.validate().is_ok());
+        assert!(!forever.expired(0, NOW));
+    }
+
+    #[test]
+    fn prune_keeps_the_strongest() {
+        let retention = Retention { half_life: 10.0, max_directories: 1, max_age: 30, forget_missing: false };
+        let mut completions = Completions::new();
+
+        completions.add_completion("make".into(), "/a".into(), &retention, NOW - 20 * DAYS);
+        completions.add_completion("make".into(), "/a".into(), &retention, NOW - 20 * DAYS);
+        completions.add_completion("make".into(), "/b".into(), &retention, NOW);
+        completions.add_completion("old".into(), "/a".into(), &retention, NOW - 40 * DAYS);
+        completions.record_run("old".into(), 0, 1.0, None, NOW - 40 * DAYS);
+
+        completions.prune(&retention, NOW);
+
+        assert_eq!(completions.entries().len(), 1);
+        assert_eq!(completions.entries()[0].1, &[(PathBuf::from("/b"), 1.0, NOW)][..]);
+    }
+
+    #[test]
+    fn failures() {
+        let mut completions = Completions::new();
+
+        completions.record_run("false".into(), 1, 0.0, None, NOW);
+        completions.record_run("false".into(), 130, 0.0, None, NOW);
+        completions.record_run("false".into(), 0, 0.0, None, NOW);
+        completions.record_run("false".into(), 1, 0.0, None, NOW);
+
+        let retention = Retention::default();
+
+        assert_eq!(completions.failure_rate("false", &retention, NOW), 0.5);
+        assert_eq!(completions.failure_rate("true", &retention, NOW), 0.0);
+        assert!(completions.remove("false"));
+        assert!(!completions.remove("false"));
+    }
+
+    #[test]
+    fn expired_entries_are_ignored() {
+        let retention = Retention { max_age: 30, forget_missing: false, ..Retention::default() };
+        let mut completions = Completions::new();
+
+        completions.add_completion("old".into(), "/".into(), &retention, NOW - 40 * DAYS);
+        completions.record_run("old".into(), 1, 1.0, None, NOW - 40 * DAYS);
+        completions.add_completion("new".into(), "/".into(), &retention, NOW);
+
+        assert_eq!(completions.get_score("old", Path::new("/"), 1.0, &retention, NOW), 0.0);
+        assert_eq!(completions.failure_rate("old", &retention, NOW), 0.0);
+        assert!(completions.get_score("new", Path::new("/"), 1.0, &retention, NOW) > 0.0);
+
+        completions.expire(&retention, NOW);
+
+        assert_eq!(completions.entries().len(), 1);
+        assert_eq!(completions.entries()[0].0, "new");
+    }
 }
@@ -16,45 +16,132 @@ use term::terminfo::TermInfo;
 
 use std::collections::HashMap;
 use std::borrow::Borrow;
+use std::cell::RefCell;
 use std::fmt::Write;
 use std::iter::FromIterator;
 
 use std::cmp;
+use std::env;
 
+use config::Config;
+use query;
+use tparm::{expand, TermStack};
+use theme::{Color, Style};
 use constants::*;
 
-#[derive(PartialEq, Clone, Debug)]
-pub enum TermStack {
-    // here for correctness
-    #[allow(dead_code)]
-    Str(String),
-    Int(isize),
-    // here for correctness
-    #[allow(dead_code)]
-    Bool(bool),
-}
+// VT100 and xterm sequences, in terminfo syntax, for what terminfo doesn't tell us
+const ANSI_CURSOR: &[(&str, &str)] = &[
+    ("cuu", "\x1b[%p1%dA"),
+    ("cub", "\x1b[%p1%dD"),
+    ("sc", "\x1b7"),
+    ("rc", "\x1b8"),
+    ("ed", "\x1b[J"),
+    ("el", "\x1b[K"),
+];
+
+const ANSI_STYLES: &[(&str, &str)] = &[
+    ("bold", "\x1b[1m"),
+    ("rev", "\x1b[7m"),
+    ("sgr0", "\x1b[m"),
+    ("setaf", "\x1b[%?%p1%{8}%<%t3%p1%d%e%p1%{16}%<%t9%p1%{8}%-%d%e38;5;%p1%d%;m"),
+    ("setab", "\x1b[%?%p1%{8}%<%t4%p1%d%e%p1%{16}%<%t10%p1%{8}%-%d%e48;5;%p1%d%;m"),
+];
 
 pub struct Line {
     line: String,
+    tag: Option<String>,
+    // indices of the characters the query matched
+    positions: Vec<usize>,
+}
+
+// the sequences that turn on each part of the theme
+pub struct Styles {
+    prompt: String,
+    query: String,
+    selected: String,
+    highlight: String,
+    tag: String,
+    position: String,
+    // turns everything off again
+    reset: String,
+}
+
+// a match being rendered, cut off at the terminal width
+struct Row<'a> {
+    result: String,
+    used: usize,
+    limit: usize,
+    styled: bool,
+    reset: &'a str,
 }
 
 pub struct Escape {
     strings: HashMap<String, String>,
+    match_number: usize,
+    prompt: String,
+    finish: String,
+    match_select: String,
+    styles: Styles,
+    // terminfo's static variables
+    statics: RefCell<Vec<TermStack>>,
 }
 
 pub struct Matches {
     matches: Vec<Line>,
+    // the search had more to give than this
+    more: bool,
+}
+
+// the part of the matches that's on screen
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct Window {
+    pub selected: usize,
+    // the first match on screen
+    pub offset: usize,
+    // rows below the prompt
+    pub height: usize,
 }
 
 impl FromIterator<String> for Matches {
     fn from_iter<T>(matches: T) -> Matches
         where T: IntoIterator<Item = String>
     {
-        Matches { matches: matches.into_iter().map(|item| Line::new(item)).collect() }
+        Matches {
+            matches: matches.into_iter().map(Line::new).collect(),
+            more: false,
+        }
     }
 }
 
 impl Matches {
+    pub fn tagged<T>(matches: T, tags: &HashMap<String, String>) -> Matches
+        where T: IntoIterator<Item = String>
+    {
+        Matches::highlighted(matches.into_iter().map(|item| (item, vec![])), tags)
+    }
+
+    pub fn highlighted<T>(matches: T, tags: &HashMap<String, String>) -> Matches
+        where T: IntoIterator<Item = (String, Vec<usize>)>
+    {
+        Matches {
+            matches: matches.into_iter().map(|(item, positions)| {
+                let tag = tags.get(&item).cloned();
+                let mut line = Line::with_tag(item, tag);
+                line.positions = positions;
+                line
+            }).collect(),
+            more: false,
+        }
+    }
+
+    pub fn set_more(&mut self, more: bool) {
+        self.more = more;
+    }
+
+    pub fn more(&self) -> bool {
+        self.more
+    }
+
     pub fn len(&self) -> usize {
         self.matches.len()
     }
@@ -63,119 +150,421 @@ impl Matches {
         self.matches.get(selected)
     }
 
-    pub fn render(&self, width: usize, selected: usize) -> String {
-        let mut result = format!("");
+    pub fn remove(&mut self, line: &str) {
+        self.matches.retain(|item| item.line != line);
+    }
+
+    pub fn render(&self, width: usize, window: &Window, select: &str, styles: &Styles) -> String {
+        let mut result = String::new();
+        let visible = window.visible(self.len());
 
-        for (i, line) in self.matches.iter().enumerate() {
-            write!(result, "{}", line.render(Some(width), i == selected)).unwrap();
+        for (i, line) in self.matches.iter().enumerate().skip(window.offset).take(visible) {
+            write!(result, "{}", line.render(Some(width), i == window.selected, select, styles)).unwrap();
+        }
+
+        if window.overflows(self.len()) {
+            let more = if self.more { "+" } else { "" };
+            let position = format!("[{}-{} of {}{}]", window.offset + 1, window.offset + visible, self.len(), more);
+            let mut row = Row::new(width, &styles.reset);
+
+            row.style(&styles.position);
+            row.push_str(&position);
+            result.push_str(&row.finish());
         }
 
         result
     }
 }
 
+impl Window {
+    pub fn new(height: usize) -> Window {
+        Window {
+            selected: 0,
+            offset: 0,
+            height,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.selected = 0;
+        self.offset = 0;
+    }
+
+    // the last row shows where we are when the matches don't fit
+    fn overflows(&self, total: usize) -> bool {
+        total > self.height && self.height > 1
+    }
+
+    pub fn visible(&self, total: usize) -> usize {
+        if self.overflows(total) {
+            self.height - 1
+        } else {
+            cmp::min(self.height, total)
+        }
+    }
+
+    // move so that the selection is on screen, returns true if we moved
+    pub fn scroll(&mut self, total: usize) -> bool {
+        let before = self.offset;
+        let visible = cmp::max(self.visible(total), 1);
+
+        if self.selected < self.offset {
+            self.offset = self.selected;
+        } else if self.selected >= self.offset + visible {
+            self.offset = self.selected + 1 - visible;
+        }
+
+        // don't leave rows empty when there are matches to fill them
+        self.offset = cmp::min(self.offset, total.saturating_sub(visible));
+
+        self.offset != before
+    }
+
+    // a smaller terminal has fewer rows, so bring the selection back on screen
+    pub fn resize(&mut self, height: usize, total: usize) -> bool {
+        self.height = height;
+        self.scroll(total)
+    }
+
+    // move by a screenful, keeping the selection on the same row
+    pub fn page(&mut self, total: usize, down: bool) {
+        let visible = cmp::max(self.visible(total), 1);
+
+        if down {
+            self.selected = cmp::min(self.selected + visible, total.saturating_sub(1));
+            self.offset += visible;
+        } else {
+            self.selected = self.selected.saturating_sub(visible);
+            self.offset = self.offset.saturating_sub(visible);
+        }
+
+        self.scroll(total);
+    }
+}
+
 impl Line {
     pub fn new(line: String) -> Line {
-        Line { line: line }
+        Line::with_tag(line, None)
+    }
+
+    pub fn with_tag(line: String, tag: Option<String>) -> Line {
+        Line { line, tag, positions: vec![] }
     }
 
     pub fn get(&self) -> &String {
         self.line.borrow()
     }
 
-    pub fn render(&self, width: Option<usize>, selected: bool) -> String {
-        let mut result;
+    pub fn render(&self, width: Option<usize>, selected: bool, select: &str, styles: &Styles) -> String {
+        let base = if selected { styles.selected.as_str() } else { "" };
+        let mut row = Row::new(width.unwrap_or(usize::MAX), &styles.reset);
 
-        if selected {
-            result = format!("{}{}{}", MATCH_PRE, MATCH_SELECT, self.line);
-        } else {
-            result = format!("{}{}", MATCH_PRE, self.line);
+        row.style(base);
+
+        if selected && !row.push_str(select) {
+            return row.finish();
         }
 
-        width.map(|size| {
-            while UnicodeWidthStr::width(result.as_str()) > size {
-                result.pop();
+        if let Some(ref tag) = self.tag {
+            row.style(&styles.tag);
+            let fits = row.push_str(&format!("[{}]", tag));
+            row.unstyle(&styles.tag, base);
+
+            if !fits || !row.push(' ') {
+                return row.finish();
             }
+        }
+
+        let mut positions = self.positions.iter().peekable();
+        let mut lit = false;
+
+        for (idx, chr) in self.line.chars().enumerate() {
+            let matched = positions.peek() == Some(&&idx);
+            if matched {
+                positions.next();
+            }
+
+            if matched != lit {
+                if matched {
+                    row.style(&styles.highlight);
+                } else {
+                    row.unstyle(&styles.highlight, base);
+                }
+
+                lit = matched;
+            }
+
+            if !row.push(printable(chr)) {
+                break;
+            }
+        }
+
+        row.finish()
+    }
+}
+
+impl<'a> Row<'a> {
+    fn new(limit: usize, reset: &'a str) -> Row<'a> {
+        Row {
+            result: MATCH_PRE.to_string(),
+            used: 0,
+            limit,
+            styled: false,
+            reset,
+        }
+    }
+
+    // returns false once the row is full
+    fn push(&mut self, chr: char) -> bool {
+        let width = chr.width().unwrap_or(0);
+
+        if self.used + width > self.limit {
+            return false;
+        }
+
+        self.used += width;
+        self.result.push(chr);
+        true
+    }
+
+    fn push_str(&mut self, text: &str) -> bool {
+        text.chars().all(|chr| self.push(chr))
+    }
+
+    fn style(&mut self, style: &str) {
+        if !style.is_empty() {
+            self.result.push_str(style);
+            self.styled = true;
+        }
+    }
+
+    // end a style, going back to the one for the whole row
+    fn unstyle(&mut self, style: &str, base: &str) {
+        if !style.is_empty() {
+            self.result.push_str(self.reset);
+            self.styled = false;
+            self.style(base);
+        }
+    }
+
+    fn finish(mut self) -> String {
+        if self.styled {
+            self.result.push_str(self.reset);
+        }
+
+        self.result
+    }
+}
+
+fn fill_missing(strings: &mut HashMap<String, String>, fallback: &[(&str, &str)]) {
+    for &(name, value) in fallback {
+        strings.entry(name.to_string()).or_insert_with(|| {
+            debug!("Using ANSI fallback for {}", name);
+            value.to_string()
         });
+    }
+}
 
-        result
+// multi-line commands have to stay on one row
+fn printable(chr: char) -> char {
+    match chr {
+        '\n' => '\u{21b5}',
+        '\t' => ' ',
+        _ if chr.is_control() => '?',
+        _ => chr,
     }
 }
 
 impl Escape {
-    pub fn create() -> Escape {
-        let info = TermInfo::from_env().expect("Failed to get terminfo");
+    pub fn create(config: &Config) -> Escape {
         let mut strings = HashMap::default();
 
-        for (name, value) in info.strings.into_iter() {
-            trace!("Inserting string {}", name);
-            strings.insert(String::from(name), String::from_utf8(value).expect("String was not utf-8"));
+        match TermInfo::from_env() {
+            Ok(info) => {
+                for (name, value) in info.strings.into_iter() {
+                    trace!("Inserting string {}", name);
+                    match String::from_utf8(value) {
+                        Ok(value) => {
+                            strings.insert(String::from(name), value);
+                        }
+                        Err(_) => debug!("String {} was not utf-8", name),
+                    }
+                }
+            }
+            Err(error) => {
+                // most terminals understand ANSI anyway, styles included
+                warn!("Failed to get terminfo, assuming ANSI: {}", error);
+                fill_missing(&mut strings, ANSI_STYLES);
+            }
+        }
+
+        Escape::from_strings(strings, config)
+    }
+
+    fn from_strings(mut strings: HashMap<String, String>, config: &Config) -> Escape {
+        // we can't draw at all without these, so don't trust an entry that lacks them
+        fill_missing(&mut strings, ANSI_CURSOR);
+
+        let mut escape = Escape {
+            strings,
+            match_number: config.match_number,
+            prompt: config.prompt.clone(),
+            finish: config.finish.clone(),
+            match_select: config.match_select.clone(),
+            styles: Styles {
+                prompt: String::new(),
+                query: String::new(),
+                selected: String::new(),
+                highlight: String::new(),
+                tag: String::new(),
+                position: String::new(),
+                reset: String::new(),
+            },
+            statics: RefCell::new(vec![TermStack::Int(0); 26]),
+        };
+
+        // https://no-color.org
+        let color = match env::var_os("NO_COLOR") {
+            Some(value) => value.is_empty(),
+            None => true,
+        };
+        let theme = &config.theme;
+
+        escape.styles = Styles {
+            prompt: escape.style(&theme.prompt, color),
+            query: escape.style(&theme.query, color),
+            selected: escape.style(&theme.selected, color),
+            highlight: escape.style(&theme.highlight, color),
+            tag: escape.style(&theme.tag, color),
+            position: escape.style(&theme.position, color),
+            reset: escape.get_string("sgr0", vec![]).unwrap_or_default(),
+        };
+
+        escape
+    }
+
+    fn style(&self, style: &Style, color: bool) -> String {
+        let mut result = String::new();
+
+        if style.bold {
+            result.push_str(&self.get_string("bold", vec![]).unwrap_or_default());
+        }
+
+        if style.reverse {
+            result.push_str(&self.get_string("rev", vec![]).unwrap_or_default());
         }
 
-        Escape { strings: strings }
+        if color {
+            let colors = [("setaf", &style.fg), ("setab", &style.bg)];
+
+            for &(name, color) in colors.iter() {
+                // the config has been validated, so the index is fine
+                if let Some(Ok(index)) = color.as_ref().map(Color::index) {
+                    let sequence = self.get_string(name, vec![TermStack::Int(index as isize)]);
+                    result.push_str(&sequence.unwrap_or_default());
+                }
+            }
+        }
+
+        result
+    }
+
+    // wrap text in a style, if there is one
+    fn paint(&self, style: &str, text: &str) -> String {
+        if style.is_empty() {
+            text.to_string()
+        } else {
+            format!("{}{}{}", style, text, self.styles.reset)
+        }
     }
 
     fn cursor_up(&self, by: usize) -> String {
         self.get_string("cuu", vec![TermStack::Int(by as isize)])
-            .unwrap_or(format!(""))
+            .unwrap_or_default()
     }
 
     pub fn restore_cursor(&self) -> String {
-        self.get_string("rc", vec![]).unwrap_or(format!(""))
+        self.get_string("rc", vec![]).unwrap_or_default()
     }
 
     pub fn save_cursor(&self) -> String {
-        self.get_string("sc", vec![]).unwrap_or(format!(""))
+        self.get_string("sc", vec![]).unwrap_or_default()
     }
 
     pub fn clear_screen(&self) -> String {
-        self.get_string("ed", vec![]).unwrap_or(format!(""))
+        self.get_string("ed", vec![]).unwrap_or_default()
+    }
+
+    // how many rows below the prompt we can use
+    pub fn height(&self, rows: usize) -> usize {
+        cmp::min(self.match_number, rows.saturating_sub(1))
     }
 
     pub fn make_space(&self, rows: usize) -> String {
-        let number = cmp::min(MATCH_NUMBER, rows - 1);
+        let number = self.height(rows);
         format!("{}{}",
-                String::from_iter(vec!['\n'; number as usize].into_iter()),
+                String::from_iter(vec!['\n'; number].into_iter()),
                 self.cursor_up(number))
     }
 
-    pub fn move_back(&self, by: usize) -> String {
-        format!("{}{}{}",
-                self.get_string("cub", vec![TermStack::Int(by as isize)])
-                    .unwrap_or(format!("")),
-                self.save_cursor(),
-                self.clear_screen())
+    fn cursor_left(&self, by: usize) -> String {
+        // a zero parameter still moves some terminals by one
+        if by == 0 {
+            return String::new();
+        }
+
+        self.get_string("cub", vec![TermStack::Int(by as isize)])
+            .unwrap_or_default()
+    }
+
+    fn clear_line(&self) -> String {
+        self.get_string("el", vec![]).unwrap_or_default()
     }
 
-    pub fn query_output(&self, chr: char) -> String {
-        format!("{}{}{}", chr, self.save_cursor(), self.clear_screen())
+    // clear everything below the query line, leaving the line itself alone
+    fn clear_below(&self) -> String {
+        format!("\n{}{}", self.clear_screen(), self.cursor_up(1))
     }
 
-    pub fn matches_output(&self, matches: &Matches, width: usize, selected: usize) -> String {
+    // redraw the query from its start, given where the cursor was and should be
+    pub fn query_output(&self, query: &str, old_cursor: usize, cursor: usize) -> String {
+        // measured like the cursor, or the two can disagree
+        let width = query::display_width(query.chars());
+
+        format!("{}{}{}{}{}",
+                self.cursor_left(old_cursor),
+                self.paint(&self.styles.query, query),
+                self.clear_line(),
+                self.cursor_left(width - cursor),
+                self.save_cursor())
+    }
+
+    pub fn matches_output(&self, matches: &Matches, width: usize, window: &Window) -> String {
         format!("{}{}{}",
-                self.clear_screen(),
-                matches.render(width, selected),
+                self.clear_below(),
+                matches.render(width, window, &self.match_select, &self.styles),
                 self.restore_cursor())
     }
 
-    pub fn match_down(&self, matches: &Matches, width: usize, selected: usize) -> String {
+    pub fn match_down(&self, matches: &Matches, width: usize, window: &Window) -> String {
         // this gets us to the first match
-        let mut result = format!("");
+        let mut result = String::new();
+        let selected = window.selected;
 
         // move down to the line before the last selection
-        write!(result, "{}", String::from_iter(vec!['\n'; selected - 1].into_iter())).unwrap();
+        write!(result, "{}", String::from_iter(vec!['\n'; selected - window.offset - 1].into_iter())).unwrap();
 
         // render the last line as non-selected
         match matches.get(selected - 1) {
             None => {
                 // no such match, do nothing
                 debug!("No such match: {}", selected - 1);
-                return format!("");
+                return String::new();
             }
             Some(line) => {
                 write!(result, "{}{}",
-                    line.render(Some(width), false),
-                    self.get_string("el", vec![]).unwrap_or(format!(""))).unwrap();
+                    line.render(Some(width), false, &self.match_select, &self.styles),
+                    self.clear_line()).unwrap();
             }
         }
 
@@ -184,12 +573,12 @@ impl Escape {
             None => {
                 // no such match, do nothing
                 debug!("No such match: {}", selected);
-                return format!("");
+                return String::new();
             }
             Some(line) => {
                 write!(result, "{}{}",
-                    line.render(Some(width), true),
-                    self.get_string("el", vec![]).unwrap_or(format!(""))).unwrap();
+                    line.render(Some(width), true, &self.match_select, &self.styles),
+                    self.clear_line()).unwrap();
             }
         }
 
@@ -200,25 +589,26 @@ impl Escape {
         result
     }
 
-    pub fn match_up(&self, matches: &Matches, width: usize, selected: usize) -> String {
+    pub fn match_up(&self, matches: &Matches, width: usize, window: &Window) -> String {
         // this gets us to the first match
-        let mut result = format!("");
+        let mut result = String::new();
+        let selected = window.selected;
 
         // move down to the line before the last selection
         write!(result, "{}",
-            String::from_iter(vec!['\n'; selected].into_iter())).unwrap();
+            String::from_iter(vec!['\n'; selected - window.offset].into_iter())).unwrap();
 
         // render the last line as selected
         match matches.get(selected) {
             None => {
                 // no such match, do nothing
                 debug!("No such match: {}", selected);
-                return format!("");
+                return String::new();
             }
             Some(line) => {
                 write!(result, "{}{}",
-                    line.render(Some(width), true),
-                    self.get_string("el", vec![]).unwrap_or(format!(""))).unwrap();
+                    line.render(Some(width), true, &self.match_select, &self.styles),
+                    self.clear_line()).unwrap();
             }
         }
 
@@ -227,12 +617,12 @@ impl Escape {
             None => {
                 // no such match, do nothing
                 debug!("No such match: {}", selected + 1);
-                return format!("");
+                return String::new();
             }
             Some(line) => {
                 write!(result, "{}{}",
-                    line.render(Some(width), false),
-                    self.get_string("el", vec![]).unwrap_or(format!(""))).unwrap();
+                    line.render(Some(width), false, &self.match_select, &self.styles),
+                    self.clear_line()).unwrap();
             }
         }
 
@@ -246,15 +636,37 @@ impl Escape {
     pub fn best_match_output(&self, matches: &Matches, selected: usize, recent: bool) -> String {
         matches.get(selected).map_or(format!("\n{}", self.clear_screen()), |line| {
             if recent {
-                format!("recent{}{}\n{}", FINISH, line.get(), self.clear_screen())
+                format!("recent{}{}\n{}", self.finish, line.get(), self.clear_screen())
             } else {
-                format!("{}{}\n{}", FINISH, line.get(), self.clear_screen())
+                format!("{}{}\n{}", self.finish, line.get(), self.clear_screen())
             }
         })
     }
 
-    pub fn render_prompt(&self, rows: usize) -> String {
-        format!("{}{}{}{}", self.make_space(rows), PROMPT, self.save_cursor(), self.clear_screen())
+    pub fn cancel_output(&self) -> String {
+        format!("\n{}", self.clear_screen())
+    }
+
+    pub fn render_prompt(&self, rows: usize, query: &str) -> String {
+        format!("{}{}{}{}{}",
+                self.make_space(rows),
+                self.paint(&self.styles.prompt, &self.prompt),
+                self.paint(&self.styles.query, query),
+                self.save_cursor(),
+                self.clear_screen())
+    }
+
+    // draw everything again from the start of the query line
+    pub fn redraw(&self, rows: usize, query: &str, cursor: usize, matches: &Matches, width: usize, window: &Window) -> String {
+        let end = query::display_width(query.chars());
+
+        format!("{}\r{}{}{}{}{}",
+                self.restore_cursor(),
+                self.clear_screen(),
+                self.render_prompt(rows, query),
+                self.cursor_left(end - cursor),
+                self.save_cursor(),
+                self.matches_output(matches, width, window))
     }
 
     pub fn bell(&self) -> String {
@@ -262,7 +674,6 @@ impl Escape {
     }
 
     fn get_string<T: Borrow<str>>(&self, name: T, params: Vec<TermStack>) -> Option<String> {
-        // only implement what we're actually using in the UI
         let sequence = match self.strings.get(name.borrow()) {
             None => {
                 trace!("No match for string: {:?}", name.borrow());
@@ -270,77 +681,236 @@ impl Escape {
             }
             Some(s) => {
                 trace!("Matched string: {:?}", s);
-                s.clone()
+                s
             }
         };
 
-        let mut escaped = false;
-        let mut stack: Vec<TermStack> = vec![];
-        let mut result = String::default();
-        let mut escape = String::default();
+        let result = expand(sequence, &params, &mut self.statics.borrow_mut());
 
-        // only implement the sequences we care about
+        trace!("Returning result: {:?}", result);
 
-        for c in sequence.chars() {
-            if !escaped {
-                if c == '%' {
-                    escaped = true;
-                } else {
-                    result.push(c);
-                }
-            } else if escape.is_empty() {
-                if c == 'd' {
-                    match stack.pop() {
-                        Some(TermStack::Int(c)) => {
-                            result.push_str(format!("{}", c).as_ref());
-                        }
-                        Some(o) => {
-                            error!("Numeric print on non-numeric type: {:?}", o);
-                        }
-                        None => {
-                            error!("Stack was empty on print");
-                        }
-                    }
-                    escaped = false;
-                } else if c == 'p' {
-                    escape.push('p');
-                } else {
-                    error!("Unknown escape character: {:?}", c);
-                    escaped = false;
-                }
-            } else {
-                if escape == "p" {
-                    match c.to_digit(10) {
-                        Some(idx) => {
-                            if idx != 0 {
-                                match params.get(idx as usize - 1) {
-                                    Some(item) => stack.push(item.clone()),
-                                    None => {
-                                        error!("There was no parameter {}", idx);
-                                    }
-                                }
-                            } else {
-                                error!("Tried to print 0th paramater");
-                            }
-                        }
-                        None => {
-                            error!("Paramater number was not a digit");
-                        }
-                    }
+        // return result
+        Some(result)
+    }
+}
 
-                    escape.clear();
-                    escaped = false;
-                } else {
-                    error!("Unknown escape sequence: {:?}", escape);
-                    escape.clear();
-                    escaped = false;
-                }
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use query::Query;
+
+    fn escape() -> Escape {
+        // only the ANSI cursor movement, and no styles
+        Escape::from_strings(HashMap::new(), &Config::default())
+    }
+
+    fn fallback(table: &[(&str, &str)], name: &str, params: &[TermStack]) -> String {
+        let sequence = table.iter().find(|&&(known, _)| known == name).unwrap().1;
+        expand(sequence, params, &mut vec![TermStack::Int(0); 26])
+    }
+
+    #[test]
+    fn ansi_fallback_colors() {
+        let color = |name, index| fallback(ANSI_STYLES, name, &[TermStack::Int(index)]);
+
+        assert_eq!(color("setaf", 3), "\x1b[33m");
+        assert_eq!(color("setaf", 11), "\x1b[93m");
+        assert_eq!(color("setaf", 100), "\x1b[38;5;100m");
+        assert_eq!(color("setab", 0), "\x1b[40m");
+        assert_eq!(color("setab", 8), "\x1b[100m");
+        assert_eq!(color("setab", 17), "\x1b[48;5;17m");
+    }
+
+    #[test]
+    fn ansi_fallback_cursor() {
+        assert_eq!(fallback(ANSI_CURSOR, "cuu", &[TermStack::Int(2)]), "\x1b[2A");
+        assert_eq!(fallback(ANSI_CURSOR, "cub", &[TermStack::Int(5)]), "\x1b[5D");
+    }
+
+    #[test]
+    fn prompt_shows_initial_query() {
+        let output = escape().render_prompt(5, "git st");
+
+        assert!(output.contains("Match: git st\x1b7"));
+    }
+
+    #[test]
+    fn cancel_leaves_the_prompt() {
+        let output = escape().cancel_output();
+
+        // below the query, clearing the matches
+        assert!(output.starts_with('\n'));
+        assert!(output.ends_with(&escape().clear_screen()));
+    }
+
+    #[test]
+    fn query_output_moves_back_to_cursor() {
+        assert_eq!(escape().query_output("ab", 0, 1), "ab\x1b[K\x1b[1D\x1b7");
+        assert_eq!(escape().query_output("ab", 2, 2), "\x1b[2Dab\x1b[K\x1b7");
+    }
+
+    #[test]
+    fn query_output_with_cursor_at_end() {
+        let texts = [
+            "\u{644}\u{627}",
+            "\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}\u{200d}\u{1f466}",
+            "\u{2764}\u{fe0f}",
+        ];
+
+        for text in texts.iter() {
+            let query = Query::new(text);
+            let output = escape().query_output(text, 0, query.cursor_width());
+
+            assert_eq!(output, format!("{}\x1b[K\x1b7", text));
         }
+    }
 
-        trace!("Returning result: {:?}", result);
+    #[test]
+    fn redraw_with_cursor_at_end() {
+        let text = "\u{644}\u{627}";
+        let query = Query::new(text);
+        let matches = Matches::from_iter(vec![]);
 
-        // return result
-        Some(result)
+        let output = escape().redraw(10, text, query.cursor_width(), &matches, 80, &Window::new(0));
+
+        assert!(output.contains(&format!("{}\x1b7", text)));
+    }
+
+    #[test]
+    fn window_scrolls_past_the_bottom() {
+        let mut window = Window::new(5);
+
+        // one row goes to the position, so four matches fit
+        assert_eq!(window.visible(20), 4);
+
+        window.selected = 3;
+        assert!(!window.scroll(20));
+        assert_eq!(window.offset, 0);
+
+        window.selected = 4;
+        assert!(window.scroll(20));
+        assert_eq!(window.offset, 1);
+
+        window.selected = 19;
+        assert!(window.scroll(20));
+        assert_eq!(window.offset, 16);
+    }
+
+    #[test]
+    fn window_scrolls_past_the_top() {
+        let mut window = Window { selected: 10, offset: 8, height: 5 };
+
+        window.selected = 8;
+        assert!(!window.scroll(20));
+
+        window.selected = 7;
+        assert!(window.scroll(20));
+        assert_eq!(window.offset, 7);
+
+        window.selected = 0;
+        assert!(window.scroll(20));
+        assert_eq!(window.offset, 0);
+    }
+
+    #[test]
+    fn window_pages() {
+        let mut window = Window::new(5);
+
+        window.page(20, true);
+        assert_eq!((window.selected, window.offset), (4, 4));
+
+        window.selected = 18;
+        window.offset = 16;
+        window.page(20, true);
+        assert_eq!((window.selected, window.offset), (19, 16));
+
+        window.page(20, false);
+        assert_eq!((window.selected, window.offset), (15, 12));
+
+        window.selected = 1;
+        window.offset = 1;
+        window.page(20, false);
+        assert_eq!((window.selected, window.offset), (0, 0));
+    }
+
+    #[test]
+    fn window_pages_when_short() {
+        let mut window = Window::new(5);
+
+        // everything fits, so paging goes to either end
+        assert_eq!(window.visible(3), 3);
+
+        window.page(3, true);
+        assert_eq!((window.selected, window.offset), (2, 0));
+
+        window.page(3, false);
+        assert_eq!((window.selected, window.offset), (0, 0));
+
+        // a single row still moves one at a time
+        let mut window = Window::new(1);
+
+        window.page(3, true);
+        assert_eq!((window.selected, window.offset), (1, 1));
+
+        window.page(3, true);
+        assert_eq!((window.selected, window.offset), (2, 2));
+
+        window.page(3, true);
+        assert_eq!((window.selected, window.offset), (2, 2));
+    }
+
+    #[test]
+    fn window_after_results_shrink() {
+        let mut window = Window { selected: 15, offset: 12, height: 5 };
+
+        window.reset();
+        assert_eq!((window.selected, window.offset), (0, 0));
+        assert!(!window.scroll(2));
+        assert_eq!(window.visible(2), 2);
+
+        // without a reset, a clamped selection pulls the window back up
+        let mut window = Window { selected: 4, offset: 12, height: 5 };
+
+        assert!(window.scroll(5));
+        assert_eq!(window.offset, 0);
+        assert_eq!(window.visible(5), 5);
+    }
+
+    #[test]
+    fn window_after_terminal_shrinks() {
+        let escape = escape();
+        let mut window = Window { selected: 8, offset: 0, height: escape.height(20) };
+
+        // eight rows, less the query line, and the selection stays put
+        assert!(window.resize(escape.height(8), 20));
+        assert_eq!(window, Window { selected: 8, offset: 3, height: 7 });
+
+        // growing again leaves the window where it is
+        assert!(!window.resize(escape.height(20), 20));
+        assert_eq!(window, Window { selected: 8, offset: 3, height: 10 });
+
+        // with only the query line left there's no room for matches at all
+        window.resize(escape.height(1), 20);
+        assert_eq!((window.selected, window.height), (8, 0));
+        assert_eq!(window.visible(20), 0);
+    }
+
+    #[test]
+    fn redraw_after_terminal_shrinks() {
+        let escape = escape();
+        let lines = (0..20).map(|idx| format!("match {}", idx));
+        let matches = Matches::from_iter(lines);
+        let mut window = Window { selected: 8, offset: 0, height: escape.height(20) };
+
+        window.resize(escape.height(4), matches.len());
+        let output = escape.redraw(4, "m", 1, &matches, 80, &window);
+
+        // two matches and the position fit below the query, the second one selected
+        assert!(output.contains("match 7"));
+        assert!(output.contains(&format!("{}match 8", escape.match_select)));
+        assert!(!output.contains("match 6"));
+        assert!(!output.contains("match 9"));
+        assert!(output.contains("[8-9 of 20]"));
     }
 }